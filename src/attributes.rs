@@ -0,0 +1,316 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Block attributes given in info strings, such as `cwd=build`.
+
+use std::time::Duration;
+
+use crate::filter::SyntaxError;
+use crate::json::Json;
+use crate::lexer::quote;
+
+/// The names of all attributes, which are never read as filter predicates.
+const NAMES: &[&str] = &["cwd", "env", "exit", "timeout", "retries", "name", "needs"];
+
+/// Settings for running a block, given as `name=value` words after the
+/// language tag, e.g. ```` ```sh:git cwd=build env=CC=clang timeout=300s ````.
+///
+/// A value may be enclosed in double quotes to contain whitespace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// The directory to run the block in, relative to the working directory
+    /// of doctest, also in a shared shell session that earlier blocks left
+    /// in another directory. In generated scripts and Dockerfiles, it is
+    /// relative to the directory the block starts in.
+    pub cwd: Option<String>,
+
+    /// The environment variables to set for the block, in order.
+    pub env: Vec<(String, String)>,
+
+    /// A non-zero exit status that counts as success for the block's
+    /// commands.
+    pub exit: Option<i32>,
+
+    /// How long the block's commands may run together before being killed.
+    pub timeout: Option<Duration>,
+
+    /// How many times to run the block again if it fails.
+    pub retries: Option<u32>,
+
+    /// The name other blocks refer to this block by.
+    pub name: Option<String>,
+
+    /// The names of the blocks that must run before this block.
+    pub needs: Vec<String>,
+}
+
+impl Attributes {
+    /// Returns whether no attribute is set.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the shell commands that change into the directory and export
+    /// the variables of these attributes.
+    pub fn setup(&self) -> Vec<String> {
+        let cwd = self.cwd.iter().map(|dir| format!("cd -- {}", quote(dir)));
+        let env = self
+            .env
+            .iter()
+            .map(|(name, value)| format!("export {}={}", name, quote(value)));
+        cwd.chain(env).collect()
+    }
+
+    /// Returns `command` extended to also succeed with the `exit` status, for
+    /// scripts that stop at the first failing command.
+    ///
+    /// The command is grouped, so that the status accepted is that of the
+    /// whole command, as when the block is run.
+    pub fn accept(&self, command: &str) -> String {
+        match self.exit {
+            Some(status) => format!("{{\n{}\n}} || [ $? -eq {} ]", command, status),
+            None => command.into(),
+        }
+    }
+
+    /// Returns these attributes as a JSON object.
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("cwd", self.cwd.as_deref().into()),
+            (
+                "env",
+                Json::Object(
+                    self.env
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.as_str().into()))
+                        .collect(),
+                ),
+            ),
+            ("exit", self.exit.map(i64::from).into()),
+            ("timeout", self.timeout.map(|t| format!("{:?}", t)).into()),
+            ("retries", self.retries.map(i64::from).into()),
+            ("name", self.name.as_deref().into()),
+            ("needs", self.needs.iter().map(String::as_str).collect()),
+        ])
+    }
+}
+
+/// Splits the attributes off the parameter of an info string.
+///
+/// Attributes are recognized as whole words outside of quotes. The returned
+/// filter has each attribute replaced by spaces, so that syntax errors in it
+/// point at the same columns as in `param`.
+pub fn split(param: &str) -> Result<(Attributes, String), SyntaxError> {
+    let mut attributes = Attributes::default();
+    let mut filter = param.to_string();
+    let mut quoted = false;
+    let mut boundary = true;
+
+    let mut offset = 0;
+    while let Some(c) = param[offset..].chars().next() {
+        if boundary && !quoted {
+            if let Some(len) = attribute(&mut attributes, &param[offset..], offset)? {
+                filter.replace_range(offset..offset + len, &" ".repeat(len));
+                offset += len;
+                boundary = false;
+                continue;
+            }
+        }
+
+        quoted ^= c == '"';
+        boundary = c.is_whitespace();
+        offset += c.len_utf8();
+    }
+
+    Ok((attributes, filter))
+}
+
+/// Parses an attribute at the start of `text`, which is at `offset` in the
+/// parameter, and returns its length.
+///
+/// This is `None` if `text` does not start with an attribute name.
+fn attribute(
+    attributes: &mut Attributes,
+    text: &str,
+    offset: usize,
+) -> Result<Option<usize>, SyntaxError> {
+    let error = |at: usize, message: String| SyntaxError {
+        offset: offset + at,
+        message,
+    };
+
+    let name = match text.split_once('=') {
+        Some((name, _)) if NAMES.contains(&name) => name,
+        _ => return Ok(None),
+    };
+
+    let start = name.len() + 1;
+    let rest = &text[start..];
+    let (value, len) = match rest.strip_prefix('"') {
+        Some(quoted) => match quoted.find('"') {
+            Some(end) => (&quoted[..end], start + end + 2),
+            None => return Err(error(start, "unterminated quote".into())),
+        },
+        None => {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..end], start + end)
+        }
+    };
+
+    let duplicate = match name {
+        "cwd" => attributes.cwd.is_some(),
+        "exit" => attributes.exit.is_some(),
+        "timeout" => attributes.timeout.is_some(),
+        "retries" => attributes.retries.is_some(),
+        "name" => attributes.name.is_some(),
+        _ => false,
+    };
+    if duplicate {
+        return Err(error(0, format!("duplicate attribute `{}`", name)));
+    }
+
+    let invalid = |what: &str| error(start, format!("expected {} after `{}=`", what, name));
+    match name {
+        "cwd" if value.is_empty() => return Err(invalid("a directory")),
+        "cwd" => attributes.cwd = Some(value.into()),
+        "exit" => match value.parse::<u8>() {
+            Ok(status) => attributes.exit = Some(status.into()),
+            Err(_) => return Err(invalid("an exit status")),
+        },
+        "timeout" => match duration(value) {
+            Some(timeout) => attributes.timeout = Some(timeout),
+            None => return Err(invalid("a duration such as `300s`")),
+        },
+        "retries" => match value.parse() {
+            Ok(retries) => attributes.retries = Some(retries),
+            Err(_) => return Err(invalid("a number of retries")),
+        },
+        "name" if !is_name(value) => return Err(invalid("a block name")),
+        "name" => attributes.name = Some(value.into()),
+        "needs" if !value.split(',').all(is_name) => {
+            return Err(invalid("a comma-separated list of block names"))
+        }
+        "needs" => attributes.needs.extend(value.split(',').map(Into::into)),
+        _ => match value.split_once('=') {
+            Some((var, value)) if is_variable(var) => {
+                attributes.env.push((var.into(), value.into()))
+            }
+            _ => return Err(error(start, "expected `env=NAME=VALUE`".into())),
+        },
+    }
+
+    Ok(Some(len))
+}
+
+/// Parses a duration such as `300s`, `5m`, `1h` or `500ms`, where a bare
+/// number is in seconds.
+fn duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<u64>().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(number.checked_mul(3600)?)),
+        _ => None,
+    }
+}
+
+/// Returns whether `name` can name a block, i.e. is neither empty nor
+/// contains commas or whitespace.
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c == ',' || c.is_whitespace())
+}
+
+/// Returns whether `name` is a valid shell variable name.
+fn is_variable(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let (attributes, filter) =
+            super::split(r#"git && PRETTY_NAME="a cwd=b" cwd=build env=CC=clang env="X=a b""#)
+                .unwrap();
+        assert_eq!(attributes.cwd.as_deref(), Some("build"));
+        assert_eq!(
+            attributes.env,
+            [("CC".into(), "clang".into()), ("X".into(), "a b".into())]
+        );
+        assert_eq!(filter.trim_end(), r#"git && PRETTY_NAME="a cwd=b""#);
+
+        let (attributes, filter) = super::split("cwd=/tmp ;ID=debian").unwrap();
+        assert_eq!(attributes.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(filter, "         ;ID=debian");
+
+        let (attributes, filter) = super::split("ID=debian && awd=x").unwrap();
+        assert!(attributes.is_empty());
+        assert_eq!(filter, "ID=debian && awd=x");
+
+        let (attributes, filter) = super::split("git exit=1 timeout=5m retries=3").unwrap();
+        assert_eq!(filter.trim(), "git");
+        assert_eq!(attributes.exit, Some(1));
+        assert_eq!(attributes.timeout, Some(Duration::from_secs(300)));
+        assert_eq!(attributes.retries, Some(3));
+        assert_eq!(
+            attributes.to_json().to_string(),
+            r#"{"cwd":null,"env":{},"exit":1,"timeout":"300s","retries":3,"name":null,"needs":[]}"#
+        );
+
+        let (attributes, _) = super::split(r#"cwd="my dir" env=A=1 env="B=it's""#).unwrap();
+        assert_eq!(
+            attributes.setup(),
+            ["cd -- 'my dir'", "export A=1", r"export B='it'\''s'"]
+        );
+        assert_eq!(attributes.accept("grep -q x f"), "grep -q x f");
+        let (attributes, _) = super::split("exit=1").unwrap();
+        assert!(attributes.setup().is_empty());
+        assert_eq!(
+            attributes.accept("grep -q x f"),
+            "{\ngrep -q x f\n} || [ $? -eq 1 ]"
+        );
+
+        let (attributes, _) = super::split("name=build needs=install,toolchain needs=git").unwrap();
+        assert_eq!(attributes.name.as_deref(), Some("build"));
+        assert_eq!(attributes.needs, ["install", "toolchain", "git"]);
+
+        for (param, offset, message) in [
+            ("git env=CC", 8, "expected `env=NAME=VALUE`"),
+            ("env=1X=a", 4, "expected `env=NAME=VALUE`"),
+            ("cwd=a cwd=b", 6, "duplicate attribute `cwd`"),
+            ("cwd=", 4, "expected a directory after `cwd=`"),
+            ("cwd=\"a b", 4, "unterminated quote"),
+            ("exit=256", 5, "expected an exit status after `exit=`"),
+            (
+                "timeout=5d",
+                8,
+                "expected a duration such as `300s` after `timeout=`",
+            ),
+            (
+                "retries=-1",
+                8,
+                "expected a number of retries after `retries=`",
+            ),
+            ("exit=1 exit=2", 7, "duplicate attribute `exit`"),
+            (
+                "needs=a,,b",
+                6,
+                "expected a comma-separated list of block names after `needs=`",
+            ),
+        ] {
+            let err = super::split(param).unwrap_err();
+            assert_eq!((err.offset, err.message.as_str()), (offset, message));
+        }
+    }
+}
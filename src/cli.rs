@@ -0,0 +1,505 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Command line argument parsing.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+
+use doctest::command::{self, Prompts};
+use doctest::{run, script, Section, Source};
+
+/// The usage summary printed on invalid arguments.
+pub const USAGE: &str = "\
+Usage: {cmd} [options] <markdown> [<os-release>] [<context>]
+       {cmd} run [options] <markdown> [<os-release>] [<context>]
+       {cmd} matrix [options] <markdown>
+       {cmd} coverage [options] <markdown>
+       {cmd} lint <markdown>
+       {cmd} workflow <markdown>
+
+Options:
+    --os-release <file>  Read os-release variables from <file>
+    --root <dir>         Locate os-release inside <dir> instead of /
+    --distro <name>      Use a built-in os-release fixture, e.g. ubuntu-22.04
+    --context <list>     Comma-separated list of enabled contexts
+    --output <dir>       Write one script per combination into <dir> (matrix)
+    --fail-on-dead       Exit with an error if any block is never selected
+                         (coverage)
+    --session            Run all blocks in one shell session instead of a
+                         fresh shell per block (run)
+    --section <path>     Select only blocks under the headings matching
+                         <path>, e.g. \"Getting Started/Install*\" (extract
+                         and run, repeatable)
+    --exclude-section <path>
+                         Exclude blocks under the headings matching <path>
+                         (extract and run, repeatable)
+    --only <name>        Select only the blocks named <name> and the blocks
+                         they need (extract and run, repeatable)
+    --explain            Print why each block was included or excluded to
+                         stderr (extract and run)
+    --format <format>    Print selected blocks as text, json, script or
+                         dockerfile (extract)
+    --preamble <text>    Commands to start scripts with, instead of
+                         `set -euo pipefail` (script format and matrix)
+    --image <key>=<image>
+                         Use <image> as the base image for os-release
+                         ID or ID:VERSION_ID <key> (dockerfile format)
+    --prompt <marker>    Treat lines starting with <marker> as commands,
+                         instead of $ (repeatable)
+    --root-prompt <marker>
+                         Treat lines starting with <marker> as commands in
+                         console blocks, instead of # (repeatable)
+
+The run command executes each selected block in a fresh bash shell,
+stopping at the first failing command and reporting where it came from.
+Commands run under bash rather than sh so that they behave as in scripts
+printed with --format script. With --session, the blocks share one shell,
+so that the working directory and environment carry forward from block to
+block. Output shown under a prompt in console blocks must match the actual
+output, where a line of `...` matches any lines, `...` within a line
+matches any text and a line ending in ` (re)` is a regular expression.
+
+Blocks may carry attributes after their filter, e.g. ```sh:git cwd=build,
+where a value may be double-quoted to contain spaces:
+    cwd=<dir>            Run the block in <dir>, relative to the directory
+                         doctest was started in
+    env=<name>=<value>   Set an environment variable (repeatable)
+    exit=<status>        Accept <status> as well as 0 as success
+    timeout=<duration>   Kill the block's commands after e.g. 300s, 5m or
+                         500ms
+    retries=<count>      Run a failed block again up to <count> times,
+                         waiting 1s before the first retry and twice as
+                         long before each further one
+    name=<name>          Name the block for --only and needs=
+    needs=<names>        Comma-separated names of blocks to run first with
+                         --only (repeatable)
+
+A --section path names one heading per /-separated segment, outermost
+first, and includes all subsections. In a segment, * matches any text, ?
+matches one character and \\ escapes the next character, e.g. a / within
+a heading. A segment of ** matches any number of headings, e.g.
+--section \"**/Fedora\".
+
+A comment such as <!-- doctest: sev && ID=fedora --> on a line of its own
+right before a heading sets a filter for that section, which every block
+in it and its subsections must match as well as its own filter.
+
+In sh blocks, every line is a command and lines starting with # are kept
+as comments. In console and shell-session blocks, only lines starting with
+a prompt are commands and the lines following them are their output.
+
+With --format json, each selected block is printed as one JSON object per
+line, holding its file, line and byte span, heading path, info string,
+effective filter and attributes, language, raw text and commands.
+
+With --format script, a bash script is printed whose header records the
+os-release identity and contexts used and in which each block is preceded
+by a comment naming its file, line and heading. A block with cwd= or env=
+runs in a subshell and the commands of a block with exit= also accept that
+status. The matrix command writes scripts in the same form.
+
+With --format dockerfile, a Dockerfile is printed whose base image is
+chosen from the os-release ID and VERSION_ID and which runs each selected
+block as one RUN instruction, which honours the block's cwd=, env= and
+exit= attributes. As in scripts, commands run under bash with
+`set -euo pipefail`, so the base image must provide bash. In an --image,
+{KEY} is replaced by the os-release value of KEY, e.g.
+--image fedora=fedora:{VERSION_ID}.
+
+When no os-release source is given, /etc/os-release is used, falling back to
+/usr/lib/os-release.
+
+The matrix command evaluates every combination of os-release source and
+context set, where each --os-release, --root and --distro adds a source
+and each --context adds a context set. Without sources, all built-in
+distros are used. Results are printed as one JSON object per line unless
+--output is given.
+
+The coverage command evaluates the same combinations and reports how many
+selected each block, flagging blocks that are never selected.
+
+The lint command checks the info string of every code block and every
+doctest directive and reports problems as <file>:<line>:<column>
+diagnostics.
+
+The workflow command prints a GitHub Actions workflow with one job per
+combination of built-in distro and context referenced by the filters in
+the document, adding jobs with several contexts for blocks that need them
+and warning about blocks that no job selects. Each job runs `doctest run`
+in a container of the distro's base image, using a static doctest binary
+built by a first job with cargo install. Distros whose images lack bash,
+such as alpine, get no job.";
+
+/// The operation to perform.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Print the commands selected for a single source and context set.
+    #[default]
+    Extract,
+
+    /// Execute the blocks selected for a single source and context set.
+    Run,
+
+    /// Evaluate every combination of sources and context sets.
+    Matrix,
+
+    /// Report how many combinations select each block.
+    Coverage,
+
+    /// Check the info strings of all code blocks.
+    Lint,
+
+    /// Print a CI workflow covering the filters of the document.
+    Workflow,
+}
+
+/// How the extract command prints the selected blocks.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// The commands of each block, one per line.
+    #[default]
+    Text,
+
+    /// One JSON object per block.
+    Json,
+
+    /// A bash script with a comment naming the origin of each block.
+    Script,
+
+    /// A Dockerfile with one `RUN` instruction per block.
+    Dockerfile,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "script" => Ok(Self::Script),
+            "dockerfile" => Ok(Self::Dockerfile),
+            _ => bail!(
+                "unknown format {} (expected text, json, script or dockerfile)",
+                name
+            ),
+        }
+    }
+}
+
+/// Parsed command line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    /// The operation to perform.
+    pub mode: Mode,
+
+    /// The markdown document to extract commands from.
+    pub markdown: PathBuf,
+
+    /// The os-release sources, in command line order.
+    pub sources: Vec<Source>,
+
+    /// The context sets, in command line order.
+    pub contexts: Vec<HashSet<String>>,
+
+    /// The directory to write per-combination scripts into.
+    pub output: Option<PathBuf>,
+
+    /// Whether blocks that are never selected are an error.
+    pub fail_on_dead: bool,
+
+    /// Whether blocks run in a fresh shell each or in one session.
+    pub run_mode: run::Mode,
+
+    /// The sections to select blocks from, or all if empty.
+    pub sections: Vec<Section>,
+
+    /// The sections to exclude blocks from.
+    pub exclude_sections: Vec<Section>,
+
+    /// The names of the blocks to select together with their dependencies.
+    pub only: Vec<String>,
+
+    /// Whether to explain the verdict for each block on stderr.
+    pub explain: bool,
+
+    /// How to print the selected blocks.
+    pub format: Format,
+
+    /// The commands to start generated scripts with, if not the default.
+    pub preamble: Option<String>,
+
+    /// The base image overrides, keyed by `ID` or `ID:VERSION_ID`.
+    pub images: Vec<(String, String)>,
+
+    /// The user prompt markers, if not the default.
+    pub prompt: Vec<String>,
+
+    /// The root prompt markers, if not the default.
+    pub root_prompt: Vec<String>,
+}
+
+impl Args {
+    /// Parses the arguments following the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut out = Self::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter().peekable();
+
+        let mode = args.next_if(|a| {
+            matches!(
+                a.as_str(),
+                "run" | "matrix" | "coverage" | "lint" | "workflow"
+            )
+        });
+        out.mode = match mode.as_deref() {
+            Some("run") => Mode::Run,
+            Some("matrix") => Mode::Matrix,
+            Some("coverage") => Mode::Coverage,
+            Some("lint") => Mode::Lint,
+            Some("workflow") => Mode::Workflow,
+            _ => Mode::Extract,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--os-release" => out.sources.push(Source::File(value()?.into())),
+                "--root" => out.sources.push(Source::Root(value()?.into())),
+                "--distro" => out.sources.push(Source::Distro(value()?)),
+                "--context" => out.contexts.push(contexts(&value()?)),
+                "--output" => out.output = Some(value()?.into()),
+                "--fail-on-dead" => out.fail_on_dead = true,
+                "--session" => out.run_mode = run::Mode::Session,
+                "--section" => out.sections.push(Section::new(&value()?)),
+                "--exclude-section" => out.exclude_sections.push(Section::new(&value()?)),
+                "--only" => out.only.push(value()?),
+                "--explain" => out.explain = true,
+                "--format" => out.format = Format::parse(&value()?)?,
+                "--preamble" => out.preamble = Some(value()?),
+                "--image" => {
+                    let value = value()?;
+                    let (key, image) = value
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("--image needs <key>=<image>, got {}", value))?;
+                    out.images.push((key.into(), image.into()));
+                }
+                "--prompt" => out.prompt.push(value()?),
+                "--root-prompt" => out.root_prompt.push(value()?),
+                "--" => positional.extend(args.by_ref()),
+                opt if opt.starts_with("--") => bail!("unknown option {}", opt),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        out.markdown = positional
+            .next()
+            .ok_or_else(|| anyhow!("missing <markdown>"))?
+            .into();
+
+        match out.mode {
+            Mode::Extract | Mode::Run => {
+                if let Some(os) = positional.next() {
+                    out.sources.push(Source::File(os.into()));
+                }
+                if let Some(cx) = positional.next() {
+                    out.contexts.push(contexts(&cx));
+                }
+                if out.sources.len() > 1 {
+                    bail!("multiple os-release sources require the matrix command");
+                }
+            }
+
+            Mode::Matrix | Mode::Coverage => (),
+
+            Mode::Lint | Mode::Workflow => {
+                if !out.sources.is_empty() || !out.contexts.is_empty() {
+                    let name = mode.as_deref().unwrap_or_default();
+                    bail!("{} does not take os-release sources or contexts", name);
+                }
+            }
+        }
+
+        if out.output.is_some() && out.mode != Mode::Matrix {
+            bail!("--output requires the matrix command");
+        }
+        if out.fail_on_dead && out.mode != Mode::Coverage {
+            bail!("--fail-on-dead requires the coverage command");
+        }
+        if out.run_mode != run::Mode::Isolated && out.mode != Mode::Run {
+            bail!("--session requires the run command");
+        }
+        if !out.only.is_empty() && !matches!(out.mode, Mode::Extract | Mode::Run) {
+            bail!("--only requires the extract or run command");
+        }
+        let sections = !out.sections.is_empty() || !out.exclude_sections.is_empty();
+        if sections && !matches!(out.mode, Mode::Extract | Mode::Run) {
+            bail!("--section and --exclude-section require the extract or run command");
+        }
+        if out.format != Format::Text && out.mode != Mode::Extract {
+            bail!("--format requires the extract command");
+        }
+        if out.preamble.is_some() && out.format != Format::Script && out.mode != Mode::Matrix {
+            bail!("--preamble requires --format script or the matrix command");
+        }
+        if !out.images.is_empty() && out.format != Format::Dockerfile {
+            bail!("--image requires --format dockerfile");
+        }
+        if out.explain && !matches!(out.mode, Mode::Extract | Mode::Run) {
+            bail!("--explain cannot be combined with matrix or coverage");
+        }
+
+        if let Some(extra) = positional.next() {
+            bail!("unexpected argument {}", extra);
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the commands to start generated scripts with.
+    pub fn preamble(&self) -> &str {
+        self.preamble.as_deref().unwrap_or(script::PREAMBLE)
+    }
+
+    /// Returns the configured prompt markers.
+    pub fn prompts(&self) -> Prompts {
+        let user = markers(&self.prompt, command::USER_PROMPT);
+        let root = markers(&self.root_prompt, command::ROOT_PROMPT);
+        Prompts::new(&user, &root)
+    }
+
+    /// Returns the union of all context sets.
+    pub fn all_contexts(&self) -> HashSet<String> {
+        self.contexts.iter().flatten().cloned().collect()
+    }
+}
+
+/// Returns the given prompt markers, or `default` if there are none.
+fn markers<'a>(markers: &'a [String], default: &'a str) -> Vec<&'a str> {
+    match markers.is_empty() {
+        true => vec![default],
+        false => markers.iter().map(String::as_str).collect(),
+    }
+}
+
+/// Splits a comma-separated list of contexts.
+fn contexts(list: &str) -> HashSet<String> {
+    list.split(',')
+        .filter(|c| !c.is_empty())
+        .map(Into::into)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn positional() {
+        let args = parse(&["README.md", "os-release", "git,sev"]).unwrap();
+        assert_eq!(args.mode, Mode::Extract);
+        assert_eq!(args.markdown, PathBuf::from("README.md"));
+        assert_eq!(args.sources, vec![Source::File("os-release".into())]);
+        assert_eq!(args.all_contexts().len(), 2);
+    }
+
+    #[test]
+    fn options() {
+        let args = parse(&["--root", "/mnt", "README.md", "--context", "git"]).unwrap();
+        assert_eq!(args.markdown, PathBuf::from("README.md"));
+        assert_eq!(args.sources, vec![Source::Root("/mnt".into())]);
+        assert!(args.all_contexts().contains("git"));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--root"]).is_err());
+        assert!(parse(&["--bogus", "README.md"]).is_err());
+        assert!(parse(&["a", "b", "c", "d"]).is_err());
+        assert!(parse(&["--distro", "arch", "--root", "/", "a"]).is_err());
+        assert!(parse(&["--distro", "arch", "a", "os-release"]).is_err());
+        assert!(parse(&["--output", "out", "a"]).is_err());
+        assert!(parse(&["--explain", "a"]).unwrap().explain);
+        assert!(parse(&["matrix", "--explain", "a"]).is_err());
+        assert_eq!(parse(&["--prompt", "%", "a"]).unwrap().prompt, ["%"]);
+        assert_eq!(
+            parse(&["--format", "json", "a"]).unwrap().format,
+            Format::Json
+        );
+        assert!(parse(&["--format", "yaml", "a"]).is_err());
+        assert!(parse(&["run", "--format", "json", "a"]).is_err());
+        assert_eq!(parse(&["a"]).unwrap().preamble(), "set -euo pipefail");
+        let args = parse(&["--format", "script", "--preamble", "set -e", "a"]).unwrap();
+        assert_eq!(args.preamble(), "set -e");
+        assert!(parse(&["--preamble", "set -e", "a"]).is_err());
+        let args = parse(&["--format", "dockerfile", "--image", "fedora:40=f:{ID}", "a"]).unwrap();
+        assert_eq!(args.images, [("fedora:40".into(), "f:{ID}".into())]);
+        assert!(parse(&["--format", "dockerfile", "--image", "fedora", "a"]).is_err());
+        assert!(parse(&["--image", "fedora=f", "a"]).is_err());
+    }
+
+    #[test]
+    fn matrix() {
+        let args = parse(&[
+            "matrix",
+            "--distro",
+            "debian-12",
+            "--distro",
+            "fedora-40",
+            "--context",
+            "git",
+            "--context",
+            "",
+            "--output",
+            "out",
+            "README.md",
+        ])
+        .unwrap();
+        assert_eq!(args.mode, Mode::Matrix);
+        assert_eq!(args.sources.len(), 2);
+        assert_eq!(args.contexts.len(), 2);
+        assert!(args.contexts[1].is_empty());
+        assert_eq!(args.output, Some("out".into()));
+
+        assert!(parse(&["matrix", "README.md", "os-release"]).is_err());
+        assert!(parse(&["matrix", "--fail-on-dead", "README.md"]).is_err());
+
+        let args = parse(&["coverage", "--fail-on-dead", "README.md"]).unwrap();
+        assert_eq!(args.mode, Mode::Coverage);
+        assert!(args.fail_on_dead);
+        assert!(parse(&["coverage", "--output", "out", "README.md"]).is_err());
+        assert_eq!(parse(&["lint", "README.md"]).unwrap().mode, Mode::Lint);
+
+        let args = parse(&["run", "--explain", "README.md", "os-release", "git"]).unwrap();
+        assert_eq!(args.mode, Mode::Run);
+        assert_eq!(args.sources, vec![Source::File("os-release".into())]);
+        assert_eq!(args.run_mode, run::Mode::Isolated);
+        let args = parse(&["run", "--session", "README.md"]).unwrap();
+        assert_eq!(args.run_mode, run::Mode::Session);
+        assert!(parse(&["--session", "README.md"]).is_err());
+        let args = parse(&["run", "--only", "build", "--only", "test", "README.md"]).unwrap();
+        assert_eq!(args.only, ["build", "test"]);
+        assert!(parse(&["matrix", "--only", "build", "README.md"]).is_err());
+        let args = parse(&[
+            "--section",
+            "A/B*",
+            "--exclude-section",
+            "**/C",
+            "README.md",
+        ])
+        .unwrap();
+        assert_eq!(args.sections, [Section::new("A/B*")]);
+        assert_eq!(args.exclude_sections, [Section::new("**/C")]);
+        assert!(parse(&["coverage", "--section", "A", "README.md"]).is_err());
+        assert!(parse(&["lint", "--distro", "arch", "README.md"]).is_err());
+        assert_eq!(
+            parse(&["workflow", "README.md"]).unwrap().mode,
+            Mode::Workflow
+        );
+        assert!(parse(&["workflow", "--context", "git", "README.md"]).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Splitting code block contents into commands.
+
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::json::Json;
+use crate::lexer::Continuation;
+
+/// The kind of code block, which decides how its lines are interpreted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lang {
+    /// A script: every line is a command, with an optional prompt.
+    Sh,
+
+    /// A console session: prompted lines are commands and the unprompted
+    /// lines following them are their expected output.
+    Console,
+}
+
+impl Lang {
+    /// Returns the canonical language tag.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sh => "sh",
+            Self::Console => "console",
+        }
+    }
+
+    /// Looks up the kind of a code block from its language tag.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "sh" => Some(Self::Sh),
+            "console" | "shell-session" => Some(Self::Console),
+            _ => None,
+        }
+    }
+}
+
+/// A command extracted from a code block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    /// The 1-based markdown line the command starts on.
+    pub line: usize,
+
+    /// The command with its prompts removed, which may span several lines.
+    pub text: String,
+
+    /// The output shown after the command in a console session, if any.
+    pub expected: Option<String>,
+}
+
+impl Command {
+    /// Returns the markdown line following the last line of the command.
+    pub fn next_line(&self) -> usize {
+        self.line + self.text.split('\n').count()
+    }
+
+    /// Returns this command as a JSON object.
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("line", self.line.into()),
+            ("text", self.text.as_str().into()),
+            ("expected", self.expected.as_deref().into()),
+        ])
+    }
+
+    /// Returns whether this is a shell comment rather than a command.
+    pub fn is_comment(&self) -> bool {
+        self.text.trim_start().starts_with('#')
+    }
+}
+
+/// The default prompt marker of an unprivileged user.
+pub const USER_PROMPT: &str = "$";
+
+/// The default prompt marker of the root user.
+pub const ROOT_PROMPT: &str = "#";
+
+/// The prompt marker of continuation lines.
+const CONTINUATION_PROMPT: &str = ">";
+
+/// The prompt markers that introduce commands.
+#[derive(Clone, Debug)]
+pub struct Prompts {
+    user: Option<Regex>,
+    root: Option<Regex>,
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Self::new(&[USER_PROMPT], &[ROOT_PROMPT])
+    }
+}
+
+impl Prompts {
+    /// Creates prompts from user and root markers, e.g. `$` and `#`.
+    ///
+    /// A marker must be followed by whitespace to count as a prompt. Root
+    /// markers are only recognized in console sessions, since a line such
+    /// as `# Install the dependencies` in a script is a comment.
+    pub fn new(user: &[impl AsRef<str>], root: &[impl AsRef<str>]) -> Self {
+        Self {
+            user: Self::regex(user),
+            root: Self::regex(root),
+        }
+    }
+
+    fn regex(markers: &[impl AsRef<str>]) -> Option<Regex> {
+        if markers.is_empty() {
+            return None;
+        }
+
+        let markers = markers
+            .iter()
+            .map(|m| regex::escape(m.as_ref()))
+            .collect::<Vec<_>>()
+            .join("|");
+        let re = format!(r"^\s*(?:{})\s+(?P<command>.*?)\s*$", markers);
+        Some(Regex::new(&re).unwrap())
+    }
+
+    /// Removes the `> ` continuation prompt from `line`, if present.
+    fn continuation(line: &str) -> &str {
+        match line.strip_prefix(CONTINUATION_PROMPT) {
+            Some(rest) => rest.strip_prefix(' ').unwrap_or(rest),
+            None => line,
+        }
+    }
+
+    /// Returns the command following a prompt on `line`, if any.
+    fn strip<'a>(&self, lang: Lang, line: &'a str) -> Option<&'a str> {
+        let root = match lang {
+            Lang::Sh => None,
+            Lang::Console => self.root.as_ref(),
+        };
+
+        self.user
+            .iter()
+            .chain(root)
+            .find_map(|re| re.captures(line))
+            .and_then(|c| c.name("command"))
+            .map(|m| m.as_str())
+    }
+}
+
+/// Splits the text of a code block into commands.
+///
+/// A command spans several lines when it ends in a backslash, an open quote,
+/// group or compound command, or is followed by the body of a here-document.
+/// In continuation lines of a prompted command, the `> ` continuation prompt
+/// is removed.
+///
+/// `line` is the markdown line of the first line of `text`. Comments in
+/// scripts are kept as commands of their own, see [`Command::is_comment`].
+///
+/// Fails if the last command is unfinished at the end of `text`, since a
+/// shell would wait for the rest of it forever.
+pub fn parse(lang: Lang, text: &str, line: usize, prompts: &Prompts) -> Result<Vec<Command>> {
+    let mut out: Vec<Command> = Vec::new();
+    let mut state = Continuation::new();
+    let mut prompted = false;
+
+    for (n, raw) in text.lines().enumerate() {
+        if let (false, Some(cmd)) = (state.is_complete(), out.last_mut()) {
+            let cont = match prompted {
+                true => Prompts::continuation(raw),
+                false => raw,
+            };
+            state.feed(cont);
+            cmd.text.push('\n');
+            cmd.text.push_str(cont);
+            continue;
+        }
+
+        let cleaned = match (lang, prompts.strip(lang, raw)) {
+            (_, Some(cleaned)) => {
+                prompted = true;
+                cleaned
+            }
+            (Lang::Sh, None) if raw.trim().is_empty() => continue,
+            (Lang::Sh, None) => {
+                prompted = false;
+                raw
+            }
+
+            // Output lines before the first prompt belong to no command.
+            (Lang::Console, None) => {
+                if let Some(cmd) = out.last_mut() {
+                    let expected = cmd.expected.get_or_insert_with(String::new);
+                    expected.push_str(raw);
+                    expected.push('\n');
+                }
+                continue;
+            }
+        };
+
+        state.feed(cleaned);
+        out.push(Command {
+            line: line + n,
+            text: cleaned.into(),
+            expected: None,
+        });
+    }
+
+    match (state.is_complete(), out.last()) {
+        (false, Some(cmd)) => Err(Error::Unfinished(cmd.line)),
+        _ => Ok(out),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn texts(commands: &[Command]) -> Vec<&str> {
+        commands.iter().map(|c| c.text.as_str()).collect()
+    }
+
+    #[test]
+    fn sh() {
+        let cmds = parse(
+            Lang::Sh,
+            "$ echo a\n# Install the dependencies\n\nx\necho c | tr c d\n$echo\n",
+            5,
+            &Prompts::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&cmds),
+            [
+                "echo a",
+                "# Install the dependencies",
+                "x",
+                "echo c | tr c d",
+                "$echo"
+            ]
+        );
+        assert_eq!(cmds[3].line, 9);
+        assert!(cmds[1].is_comment());
+        assert!(!cmds[3].is_comment());
+        assert!(cmds.iter().all(|c| c.expected.is_none()));
+    }
+
+    #[test]
+    fn console() {
+        let cmds = parse(
+            Lang::Console,
+            "Banner\n$ uname\nLinux\n# cd /tmp\n$ ls -1\na\n\nb\n",
+            10,
+            &Prompts::default(),
+        )
+        .unwrap();
+        assert_eq!(texts(&cmds), ["uname", "cd /tmp", "ls -1"]);
+        assert_eq!(cmds[0].line, 11);
+        assert_eq!(cmds[0].expected.as_deref(), Some("Linux\n"));
+        assert_eq!(cmds[1].expected, None);
+        assert_eq!(cmds[2].line, 14);
+        assert_eq!(cmds[2].expected.as_deref(), Some("a\n\nb\n"));
+    }
+
+    #[test]
+    fn prompts() {
+        let prompts = Prompts::new(&["%", ">>>"], &["root#"]);
+        let cmds = parse(
+            Lang::Console,
+            "% make\nok\n$ true\nroot# reboot\n",
+            1,
+            &prompts,
+        )
+        .unwrap();
+        assert_eq!(texts(&cmds), ["make", "reboot"]);
+        assert_eq!(cmds[0].expected.as_deref(), Some("ok\n$ true\n"));
+
+        let cmds = parse(Lang::Sh, ">>> ls\nroot# ls\n", 1, &prompts).unwrap();
+        assert_eq!(texts(&cmds), ["ls", "root# ls"]);
+    }
+
+    #[test]
+    fn multiline() {
+        let sh = "cargo build \\\n  --release\nf() {\n  echo a\n}\ncat <<EOF >x\n\n> b\nEOF\necho 'a\nb'\n";
+        let cmds = parse(Lang::Sh, sh, 1, &Prompts::default()).unwrap();
+        assert_eq!(
+            texts(&cmds),
+            [
+                "cargo build \\\n  --release",
+                "f() {\n  echo a\n}",
+                "cat <<EOF >x\n\n> b\nEOF",
+                "echo 'a\nb'"
+            ]
+        );
+        assert_eq!(cmds[1].line, 3);
+        assert_eq!(cmds[1].next_line(), 6);
+
+        let console = "$ cat <<EOF\n> a\n>\n> EOF\na\n\n$ echo \"x\n> y\"\nx\ny\n";
+        let cmds = parse(Lang::Console, console, 1, &Prompts::default()).unwrap();
+        assert_eq!(texts(&cmds), ["cat <<EOF\na\n\nEOF", "echo \"x\ny\""]);
+        assert_eq!(cmds[0].expected.as_deref(), Some("a\n\n"));
+        assert_eq!(cmds[1].line, 7);
+        assert_eq!(cmds[1].next_line(), 9);
+
+        let console = "$ echo $((1 << 2))\n4\n$ echo done\ndone\n";
+        let cmds = parse(Lang::Console, console, 1, &Prompts::default()).unwrap();
+        assert_eq!(texts(&cmds), ["echo $((1 << 2))", "echo done"]);
+        assert_eq!(cmds[0].expected.as_deref(), Some("4\n"));
+    }
+
+    #[test]
+    fn unfinished() {
+        for (text, line) in [
+            ("echo don't panic\necho next\n", 3),
+            ("echo a\nmake \\\n", 4),
+            ("cat <<EOF\nbody\n", 3),
+            ("f() {\n  echo a\n", 3),
+        ] {
+            let err = parse(Lang::Sh, text, 3, &Prompts::default()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "line {}: command is unfinished at the end of its block",
+                    line
+                )
+            );
+        }
+
+        let err = parse(Lang::Console, "$ echo \"a\na\n", 1, &Prompts::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: command is unfinished at the end of its block"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Ordering of named blocks by their dependencies.
+
+use crate::error::{Error, Result};
+use crate::markdown::Block;
+
+/// How far the depth-first search has got with a block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+/// The blocks reachable from a set of names through `needs` attributes.
+struct Graph<'a, 'b> {
+    blocks: &'b [&'a Block],
+    state: Vec<State>,
+    path: Vec<usize>,
+}
+
+impl Graph<'_, '_> {
+    /// Returns the indices of the blocks named `name`.
+    ///
+    /// `line` is the line of the block needing them, if any.
+    fn named(&self, name: &str, line: Option<usize>) -> Result<Vec<usize>> {
+        let found = (0..self.blocks.len())
+            .filter(|&i| self.blocks[i].attributes.name.as_deref() == Some(name))
+            .collect::<Vec<_>>();
+        match found.is_empty() {
+            true => Err(Error::UnknownName {
+                line,
+                name: name.into(),
+            }),
+            false => Ok(found),
+        }
+    }
+
+    /// Marks the block at `index` and everything it needs as reachable.
+    fn visit(&mut self, index: usize) -> Result<()> {
+        match self.state[index] {
+            State::Done => return Ok(()),
+            State::Visiting => {
+                let start = self.path.iter().position(|&i| i == index).unwrap_or(0);
+                let cycle = self.path[start..]
+                    .iter()
+                    .chain([&index])
+                    .map(|&i| {
+                        let block = self.blocks[i];
+                        (
+                            block.line,
+                            block.attributes.name.clone().unwrap_or_default(),
+                        )
+                    })
+                    .collect();
+                return Err(Error::Cycle(cycle));
+            }
+            State::Unvisited => (),
+        }
+
+        self.state[index] = State::Visiting;
+        self.path.push(index);
+        let block = self.blocks[index];
+        for need in &block.attributes.needs {
+            for i in self.named(need, Some(block.line))? {
+                self.visit(i)?;
+            }
+        }
+        self.path.pop();
+        self.state[index] = State::Done;
+        Ok(())
+    }
+
+    /// Returns whether every block that the block at `index` needs is done.
+    fn ready(&self, index: usize, emitted: &[bool]) -> bool {
+        self.blocks[index].attributes.needs.iter().all(|need| {
+            (0..self.blocks.len())
+                .filter(|&i| self.blocks[i].attributes.name.as_ref() == Some(need))
+                .all(|i| emitted[i])
+        })
+    }
+}
+
+/// Returns the blocks named by `names` together with all blocks they need,
+/// directly or transitively, in an order that runs every block after the
+/// blocks it needs.
+///
+/// Several blocks may share a name, e.g. the install steps for different
+/// distros, in which case needing the name needs all of them. Blocks that
+/// do not depend on each other keep their document order. Names are looked
+/// up among `blocks` only, so a block that was not selected cannot satisfy
+/// a dependency.
+pub fn resolve<'a>(blocks: &[&'a Block], names: &[String]) -> Result<Vec<&'a Block>> {
+    let mut graph = Graph {
+        blocks,
+        state: vec![State::Unvisited; blocks.len()],
+        path: Vec::new(),
+    };
+    for name in names {
+        for i in graph.named(name, None)? {
+            graph.visit(i)?;
+        }
+    }
+
+    let mut emitted = vec![false; blocks.len()];
+    let mut order = Vec::new();
+    while let Some(next) = (0..blocks.len())
+        .find(|&i| graph.state[i] == State::Done && !emitted[i] && graph.ready(i, &emitted))
+    {
+        emitted[next] = true;
+        order.push(blocks[next]);
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::Prompts;
+    use crate::markdown;
+
+    #[test]
+    fn resolve() {
+        let md = "```sh:name=install ID=debian\napt install -y gcc\n```\n\n\
+                  ```sh:name=install ID=fedora\ndnf install -y gcc\n```\n\n\
+                  ```sh:name=toolchain\nrustup default stable\n```\n\n\
+                  ```sh:name=docs\nmake docs\n```\n\n\
+                  ```sh:name=build needs=toolchain,install\nmake\n```\n\n\
+                  ```sh:name=test needs=build\nmake test\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let blocks = blocks.iter().collect::<Vec<_>>();
+
+        let order = super::resolve(&blocks, &["test".into()]).unwrap();
+        assert_eq!(
+            order.iter().map(|b| b.line).collect::<Vec<_>>(),
+            [1, 5, 9, 17, 21]
+        );
+
+        let order = super::resolve(&blocks, &["docs".into(), "toolchain".into()]).unwrap();
+        assert_eq!(order.iter().map(|b| b.line).collect::<Vec<_>>(), [9, 13]);
+
+        let order = super::resolve(&blocks[1..], &["test".into()]).unwrap();
+        assert_eq!(
+            order.iter().map(|b| b.line).collect::<Vec<_>>(),
+            [5, 9, 17, 21]
+        );
+
+        let err = super::resolve(&blocks, &["deploy".into()]).unwrap_err();
+        assert_eq!(err.to_string(), "no selected block is named `deploy`");
+        let err = super::resolve(&blocks[2..], &["build".into()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 17: no selected block is named `install`"
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let md =
+            "```sh:name=a needs=b\n```\n\n```sh:name=b needs=c\n```\n\n```sh:name=c needs=a\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let blocks = blocks.iter().collect::<Vec<_>>();
+
+        let err = super::resolve(&blocks, &["b".into()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency cycle: `b` (line 4) needs `c` (line 7) needs `a` (line 1) needs `b` (line 4)"
+        );
+    }
+}
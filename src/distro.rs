@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Built-in os-release fixtures for commonly supported distributions.
+//!
+//! These allow computing a distribution's command list without access to one
+//! of its os-release files, e.g. `--distro ubuntu-22.04`.
+
+use crate::error::{Error, Result};
+
+/// The embedded os-release files, keyed by distribution name.
+const DISTROS: &[(&str, &str)] = &[
+    ("almalinux-9", include_str!("distros/almalinux-9")),
+    ("alpine-3.19", include_str!("distros/alpine-3.19")),
+    ("arch", include_str!("distros/arch")),
+    ("centos-stream-9", include_str!("distros/centos-stream-9")),
+    ("debian-11", include_str!("distros/debian-11")),
+    ("debian-12", include_str!("distros/debian-12")),
+    ("fedora-39", include_str!("distros/fedora-39")),
+    ("fedora-40", include_str!("distros/fedora-40")),
+    (
+        "opensuse-leap-15.5",
+        include_str!("distros/opensuse-leap-15.5"),
+    ),
+    (
+        "opensuse-tumbleweed",
+        include_str!("distros/opensuse-tumbleweed"),
+    ),
+    ("rocky-9", include_str!("distros/rocky-9")),
+    ("ubuntu-20.04", include_str!("distros/ubuntu-20.04")),
+    ("ubuntu-22.04", include_str!("distros/ubuntu-22.04")),
+    ("ubuntu-24.04", include_str!("distros/ubuntu-24.04")),
+];
+
+/// Returns the names of all built-in distributions.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    DISTROS.iter().map(|(name, _)| *name)
+}
+
+/// Returns the raw os-release file of the named distribution.
+pub fn get(name: &str) -> Result<&'static str> {
+    DISTROS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, os)| *os)
+        .ok_or_else(|| Error::UnknownDistro(name.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::os_release;
+
+    #[test]
+    fn fixtures() {
+        for name in names() {
+            let os = os_release::parse(get(name).unwrap()).unwrap();
+            let id = os.get("ID").unwrap();
+            assert!(name.starts_with(id.as_str()), "{} has ID={}", name, id);
+        }
+
+        assert!(get("debian-12").unwrap().contains("bookworm"));
+        assert!(get("plan9").is_err());
+    }
+}
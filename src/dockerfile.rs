@@ -0,0 +1,241 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rendering of selected blocks as a Dockerfile.
+
+use std::path::Path;
+
+use regex::{Captures, Regex};
+
+use crate::error::{Error, Result};
+use crate::lexer::Continuation;
+use crate::markdown::Block;
+use crate::os_release::Facts;
+use crate::{run, script};
+
+/// The base image of each distribution `ID`, where `{KEY}` is replaced by
+/// the os-release value of `KEY`.
+const IMAGES: &[(&str, &str)] = &[
+    ("almalinux", "almalinux:{VERSION_ID}"),
+    ("alpine", "alpine:{VERSION_ID}"),
+    ("arch", "archlinux:latest"),
+    ("centos", "quay.io/centos/centos:stream{VERSION_ID}"),
+    ("debian", "debian:{VERSION_ID}"),
+    ("fedora", "fedora:{VERSION_ID}"),
+    ("opensuse-leap", "opensuse/leap:{VERSION_ID}"),
+    ("opensuse-tumbleweed", "opensuse/tumbleweed:latest"),
+    ("rocky", "rockylinux:{VERSION_ID}"),
+    ("ubuntu", "ubuntu:{VERSION_ID}"),
+];
+
+/// Chooses the base image for the os-release variables in `os`.
+///
+/// An override keyed by `ID:VERSION_ID` takes precedence over one keyed by
+/// `ID`, which takes precedence over the built-in mapping.
+pub fn image(os: &Facts, overrides: &[(String, String)]) -> Result<String> {
+    let id = os.get("ID").ok_or(Error::NoImage(None))?;
+    let version = os.get("VERSION_ID").map(String::as_str).unwrap_or_default();
+    let exact = format!("{}:{}", id, version);
+
+    let lookup = |key: &str| {
+        overrides
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    let template = lookup(&exact)
+        .or_else(|| lookup(id))
+        .or_else(|| IMAGES.iter().find(|(k, _)| k == id).map(|(_, v)| *v))
+        .ok_or_else(|| Error::NoImage(Some(id.clone())))?;
+
+    let placeholder = Regex::new(r"\{([A-Za-z0-9_]+)\}").unwrap();
+    let mut missing = None;
+    let image = placeholder.replace_all(template, |c: &Captures<'_>| match os.get(&c[1]) {
+        Some(value) => value.clone(),
+        None => {
+            missing.get_or_insert_with(|| c[1].to_string());
+            String::new()
+        }
+    });
+    if let Some(key) = missing {
+        return Err(Error::ImageKey {
+            image: template.into(),
+            key,
+        });
+    }
+
+    Ok(image.into())
+}
+
+/// Formats the commands of a block as the argument of a `RUN` instruction.
+///
+/// Commands are chained with `&&` so that the first failure stops the build,
+/// after changing into the directory and exporting the variables of the
+/// block's attributes, which thus apply to this block only, as when it is
+/// run.
+///
+/// Blocks with commands spanning several lines, such as here-documents, or
+/// with comments, which would hide the rest of the chain, are instead passed
+/// to a shell as a here-document of their own.
+fn run_instruction(block: &Block) -> String {
+    let commands = block
+        .attributes
+        .setup()
+        .into_iter()
+        .chain(
+            block
+                .commands
+                .iter()
+                .filter(|c| !c.is_comment())
+                .map(|c| block.attributes.accept(&c.text)),
+        )
+        .collect::<Vec<_>>();
+
+    let continued = |text: &String| text.lines().rev().skip(1).all(|l| l.ends_with('\\'));
+    let commented = |text: &String| {
+        let mut continuation = Continuation::new();
+        text.lines().for_each(|line| continuation.feed(line));
+        continuation.has_comment()
+    };
+    if commands.iter().all(|c| continued(c) && !commented(c)) {
+        return format!("RUN {}\n", commands.join(" \\\n && "));
+    }
+
+    let mut out = format!("RUN <<'DOCTEST'\n{}\n", script::PREAMBLE);
+    for command in commands {
+        out.push_str(&command);
+        out.push('\n');
+    }
+    out.push_str("DOCTEST\n");
+    out
+}
+
+/// The `SHELL` instruction running commands under [`run::SHELL`] with the
+/// options of [`script::PREAMBLE`], so that blocks behave as when they are
+/// run or printed as a script.
+const SHELL: &str = r#"SHELL ["/bin/bash", "-euo", "pipefail", "-c"]"#;
+
+/// Renders a Dockerfile that builds `image` and runs `blocks` in order.
+///
+/// Each block becomes one `RUN` instruction, preceded by a comment giving
+/// its location in `file`.
+pub fn render(file: &Path, image: &str, blocks: &[&Block]) -> String {
+    let mut out = format!(
+        "# syntax=docker/dockerfile:1\n# Generated by doctest from {}\nFROM {}\n{}\n",
+        file.display(),
+        image,
+        SHELL
+    );
+
+    for block in blocks {
+        if block.commands.iter().all(|c| c.is_comment()) {
+            continue;
+        }
+        out.push_str(&format!("\n# {}\n", run::location(file, block)));
+        out.push_str(&run_instruction(block));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::Prompts;
+    use crate::{distro, markdown, os_release};
+
+    fn os(name: &str) -> Facts {
+        os_release::read(distro::get(name).unwrap().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn image() {
+        assert_eq!(
+            super::image(&os("ubuntu-22.04"), &[]).unwrap(),
+            "ubuntu:22.04"
+        );
+        assert_eq!(super::image(&os("arch"), &[]).unwrap(), "archlinux:latest");
+        assert_eq!(
+            super::image(&os("centos-stream-9"), &[]).unwrap(),
+            "quay.io/centos/centos:stream9"
+        );
+
+        let overrides = [
+            (
+                "fedora".to_string(),
+                "registry.fedoraproject.org/fedora:{VERSION_ID}".to_string(),
+            ),
+            ("fedora:39".to_string(), "fedora:39-custom".to_string()),
+            ("arch".to_string(), "archlinux:{VERSION_ID}".to_string()),
+        ];
+        assert_eq!(
+            super::image(&os("fedora-40"), &overrides).unwrap(),
+            "registry.fedoraproject.org/fedora:40"
+        );
+        assert_eq!(
+            super::image(&os("fedora-39"), &overrides).unwrap(),
+            "fedora:39-custom"
+        );
+        assert!(super::image(&os("arch"), &overrides).is_err());
+
+        let mut unknown = Facts::new();
+        unknown.insert("ID".to_string(), "gentoo".to_string());
+        assert!(super::image(&unknown, &[]).is_err());
+    }
+
+    #[test]
+    fn render() {
+        let md = "# Install\n\n```sh\n# Update first\napt update\napt install -y \\\n  git\n```\n\n```sh\ncat <<EOF >/etc/motd\nhello\nEOF\n```\n\n```sh\n# nothing\n```\n\n\
+                  ```sh\napt-get update # refresh index\napt-get install -y git\necho '#1'\n```\n\n\
+                  ```sh:cwd=/src env=CC=clang\nmake\n```\n\n\
+                  ```sh:exit=1\ngrep -q x f\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let dockerfile = super::render(
+            Path::new("README.md"),
+            "debian:12",
+            &blocks.iter().collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            dockerfile,
+            "# syntax=docker/dockerfile:1
+# Generated by doctest from README.md
+FROM debian:12
+SHELL [\"/bin/bash\", \"-euo\", \"pipefail\", \"-c\"]
+
+# README.md:3 (Install)
+RUN apt update \\
+ && apt install -y \\
+  git
+
+# README.md:10 (Install)
+RUN <<'DOCTEST'
+set -euo pipefail
+cat <<EOF >/etc/motd
+hello
+EOF
+DOCTEST
+
+# README.md:20 (Install)
+RUN <<'DOCTEST'
+set -euo pipefail
+apt-get update # refresh index
+apt-get install -y git
+echo '#1'
+DOCTEST
+
+# README.md:26 (Install)
+RUN cd -- /src \\
+ && export CC=clang \\
+ && make
+
+# README.md:30 (Install)
+RUN <<'DOCTEST'
+set -euo pipefail
+{
+grep -q x f
+} || [ $? -eq 1 ]
+DOCTEST
+"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! The error type of this crate.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::distro;
+use crate::filter::{Op, SyntaxError};
+
+/// A specialized `Result` for this crate's [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error while reading documents, os-release files or running commands.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An I/O operation failed; `context` describes the operation.
+    Io { context: String, error: io::Error },
+
+    /// A line of an os-release file is not a valid assignment.
+    OsRelease {
+        line: usize,
+        message: String,
+        text: String,
+    },
+
+    /// No os-release file exists inside a root filesystem.
+    NoOsRelease(PathBuf),
+
+    /// No built-in distribution has the given name.
+    UnknownDistro(String),
+
+    /// An os-release source could not be loaded.
+    Source { source: String, error: Box<Error> },
+
+    /// The filter in the info string of a block is malformed.
+    InvalidFilter {
+        line: usize,
+        filter: String,
+        error: SyntaxError,
+    },
+
+    /// An attribute in the info string of a block is malformed.
+    InvalidAttribute {
+        line: usize,
+        info: String,
+        error: SyntaxError,
+    },
+
+    /// A `<!-- doctest: ... -->` directive at the given line is not
+    /// followed by a heading.
+    StrayDirective(usize),
+
+    /// A version comparison met an os-release value of `key` that is not a
+    /// numeric version.
+    OsNotAVersion { key: String, op: Op, value: String },
+
+    /// A version comparison on `key` met a value in the filter that is not
+    /// a numeric version.
+    FilterNotAVersion { key: String, op: Op, value: String },
+
+    /// The command starting at the given line is still unfinished at the
+    /// end of its block, e.g. because of an unmatched quote.
+    Unfinished(usize),
+
+    /// Evaluating the filter of the block at `line` failed.
+    Block { line: usize, error: Box<Error> },
+
+    /// No base image could be chosen for the distribution `id`, or the
+    /// os-release file has no `ID` at all.
+    NoImage(Option<String>),
+
+    /// A base image refers to an os-release key that is not set.
+    ImageKey { image: String, key: String },
+
+    /// No selected block has the name needed by the block at `line`, or
+    /// asked for on the command line if `line` is `None`.
+    UnknownName { line: Option<usize>, name: String },
+
+    /// Named blocks need each other in a cycle, given as the line and name
+    /// of each block along it, starting and ending with the same block.
+    Cycle(Vec<(usize, String)>),
+
+    /// The shell running commands did not follow the expected protocol.
+    Shell(String),
+}
+
+impl Error {
+    /// Returns a closure wrapping an I/O error with a description.
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |error| Self::Io { context, error }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { context, error } => write!(f, "{}: {}", context, error),
+            Self::OsRelease {
+                line,
+                message,
+                text,
+            } => write!(f, "invalid os-release line {}: {}: {}", line, message, text),
+            Self::NoOsRelease(root) => {
+                write!(f, "no os-release file found in {}", root.display())
+            }
+            Self::UnknownDistro(name) => write!(
+                f,
+                "unknown distro `{}` (known: {})",
+                name,
+                distro::names().collect::<Vec<_>>().join(", ")
+            ),
+            Self::Source { source, error } => write!(f, "{}: {}", source, error),
+            Self::InvalidFilter {
+                line,
+                filter,
+                error,
+            } => write!(f, "line {}: invalid filter `{}`: {}", line, filter, error),
+            Self::InvalidAttribute { line, info, error } => {
+                write!(
+                    f,
+                    "line {}: invalid attribute in `{}`: {}",
+                    line, info, error
+                )
+            }
+            Self::StrayDirective(line) => write!(
+                f,
+                "line {}: doctest directive must be followed by a heading",
+                line
+            ),
+            Self::OsNotAVersion { key, op, value } => write!(
+                f,
+                "cannot compare {}={:?} with `{}`: not a numeric version",
+                key, value, op
+            ),
+            Self::FilterNotAVersion { key, op, value } => write!(
+                f,
+                "cannot compare {} with {:?} using `{}`: not a numeric version",
+                key, value, op
+            ),
+            Self::Unfinished(line) => write!(
+                f,
+                "line {}: command is unfinished at the end of its block",
+                line
+            ),
+            Self::Block { line, error } => write!(f, "line {}: {}", line, error),
+            Self::NoImage(None) => f.write_str("os-release has no ID to choose a base image"),
+            Self::NoImage(Some(id)) => write!(
+                f,
+                "no base image known for ID={}; use --image {}=<image>",
+                id, id
+            ),
+            Self::ImageKey { image, key } => write!(
+                f,
+                "base image {} needs {}, which os-release does not set",
+                image, key
+            ),
+            Self::UnknownName {
+                line: Some(line),
+                name,
+            } => write!(f, "line {}: no selected block is named `{}`", line, name),
+            Self::UnknownName { line: None, name } => {
+                write!(f, "no selected block is named `{}`", name)
+            }
+            Self::Cycle(blocks) => {
+                f.write_str("dependency cycle: ")?;
+                for (i, (line, name)) in blocks.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" needs ")?;
+                    }
+                    write!(f, "`{}` (line {})", name, line)?;
+                }
+                Ok(())
+            }
+            Self::Shell(message) => f.write_str(message),
+        }
+    }
+}
+
+// The messages of wrapped errors are part of `Display`, so `source` is left
+// unimplemented to avoid printing them twice in error chains.
+impl std::error::Error for Error {}
@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Matching of actual command output against documented expected output.
+//!
+//! Expected output is compared line by line, ignoring trailing whitespace
+//! and trailing blank lines. Within an expected line:
+//!
+//! * a line consisting only of `...` matches any number of lines, including
+//!   none;
+//! * `...` elsewhere in a line matches any text within that line;
+//! * a line ending in ` (re)` is a regular expression that must match the
+//!   whole actual line.
+
+use std::fmt::Write;
+
+use regex::Regex;
+
+/// The marker matching any text, or any lines when it stands alone.
+const ELLIPSIS: &str = "...";
+
+/// The suffix marking an expected line as a regular expression.
+const REGEX: &str = " (re)";
+
+/// One line of expected output.
+enum Pattern<'a> {
+    /// Matches any number of lines.
+    Lines,
+
+    /// Matches a single line.
+    Line(&'a str),
+}
+
+impl<'a> Pattern<'a> {
+    fn new(line: &'a str) -> Self {
+        match line.trim() {
+            ELLIPSIS => Self::Lines,
+            _ => Self::Line(line),
+        }
+    }
+
+    /// Returns whether this pattern matches a single actual line.
+    fn matches(&self, actual: &str) -> bool {
+        let line = match self {
+            Self::Lines => return true,
+            Self::Line(line) => *line,
+        };
+
+        if let Some(re) = line.strip_suffix(REGEX) {
+            return Regex::new(&format!("^(?:{})$", re)).is_ok_and(|re| re.is_match(actual));
+        }
+
+        let mut parts = line.split(ELLIPSIS);
+        let first = parts.next().unwrap_or_default();
+        let mut rest = match actual.strip_prefix(first) {
+            Some(rest) => rest,
+            None => return false,
+        };
+
+        let parts = parts.collect::<Vec<_>>();
+        match parts.split_last() {
+            None => rest.is_empty(),
+            Some((last, middle)) => {
+                for part in middle {
+                    match rest.find(part) {
+                        Some(i) => rest = &rest[i + part.len()..],
+                        None => return false,
+                    }
+                }
+                rest.ends_with(last)
+            }
+        }
+    }
+}
+
+/// One step in the alignment of expected and actual lines.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+    /// The expected line at the index matched the actual line at the index.
+    Same(usize, usize),
+
+    /// The actual line at the index was absorbed by an ellipsis.
+    Skip(usize),
+
+    /// The expected line at the index has no actual counterpart.
+    Missing(usize),
+
+    /// The actual line at the index has no expected counterpart.
+    Extra(usize),
+}
+
+/// Splits output into lines, ignoring trailing whitespace and blank lines.
+fn lines(text: &str) -> Vec<&str> {
+    let mut lines = text.lines().map(str::trim_end).collect::<Vec<_>>();
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines
+}
+
+/// Computes a minimal alignment of the expected and actual lines.
+///
+/// Matched lines and lines absorbed by an ellipsis are free, everything else
+/// costs one, so the output matches exactly when no step costs anything.
+fn align(expected: &[&str], actual: &[&str]) -> Vec<Op> {
+    let patterns = expected.iter().map(|l| Pattern::new(l)).collect::<Vec<_>>();
+    let (n, m) = (patterns.len(), actual.len());
+
+    // cost[i][j] is the cost of aligning expected[i..] with actual[j..].
+    let mut cost = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            cost[i][j] = match (patterns.get(i), j < m) {
+                (None, _) => m - j,
+                (Some(Pattern::Lines), true) => cost[i + 1][j].min(cost[i][j + 1]),
+                (Some(Pattern::Lines), false) => cost[i + 1][j],
+                (Some(p), true) if p.matches(actual[j]) => cost[i + 1][j + 1],
+                (Some(_), true) => 1 + cost[i + 1][j].min(cost[i][j + 1]),
+                (Some(_), false) => 1 + cost[i + 1][j],
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        let op = match (patterns.get(i), j < m) {
+            (None, _) => Op::Extra(j),
+            (Some(Pattern::Lines), true) if cost[i][j + 1] < cost[i + 1][j] => Op::Skip(j),
+            (Some(Pattern::Lines), _) => {
+                i += 1;
+                continue;
+            }
+            (Some(p), true) if p.matches(actual[j]) => Op::Same(i, j),
+            (Some(_), true) if cost[i][j + 1] < cost[i + 1][j] => Op::Extra(j),
+            (Some(_), _) => Op::Missing(i),
+        };
+        match op {
+            Op::Same(..) => (i, j) = (i + 1, j + 1),
+            Op::Skip(..) | Op::Extra(..) => j += 1,
+            Op::Missing(..) => i += 1,
+        }
+        ops.push(op);
+    }
+
+    ops
+}
+
+/// Returns whether the actual output matches the expected output.
+pub fn matches(expected: &str, actual: &str) -> bool {
+    align(&lines(expected), &lines(actual))
+        .iter()
+        .all(|op| matches!(op, Op::Same(..) | Op::Skip(..)))
+}
+
+/// Formats a unified diff from the expected to the actual output.
+///
+/// `line` is the markdown line of the first expected line, so that the hunk
+/// header points into the document.
+pub fn diff(expected: &str, actual: &str, line: usize) -> String {
+    let expected = lines(expected);
+    let actual = lines(actual);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "--- expected");
+    let _ = writeln!(out, "+++ actual");
+    let _ = writeln!(
+        out,
+        "@@ -{},{} +1,{} @@",
+        line,
+        expected.len(),
+        actual.len()
+    );
+    for op in align(&expected, &actual) {
+        let _ = match op {
+            Op::Same(_, j) | Op::Skip(j) => writeln!(out, " {}", actual[j]),
+            Op::Missing(i) => writeln!(out, "-{}", expected[i]),
+            Op::Extra(j) => writeln!(out, "+{}", actual[j]),
+        };
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("a\nb\n", "a  \nb\n\n"));
+        assert!(!matches("a\nb\n", "a\nc\n"));
+        assert!(matches(
+            "rustc ... (...)\n",
+            "rustc 1.75.0 (82e1608df 2023-12-21)"
+        ));
+        assert!(!matches("rustc ...\n", "cargo 1.75.0"));
+        assert!(matches("...abc\n", "xxabc"));
+        assert!(!matches("abc...\n", "ab"));
+        assert!(matches("start\n...\nend\n", "start\n1\n2\n3\nend\n"));
+        assert!(matches("start\n...\nend\n", "start\nend\n"));
+        assert!(!matches("start\n...\nend\n", "start\n1\n"));
+        assert!(matches("[0-9a-f]{7} (re)\n", "82e1608"));
+        assert!(!matches("[0-9a-f]{7} (re)\n", "82e1608df"));
+        assert!(matches("", "\n"));
+    }
+
+    #[test]
+    fn diff() {
+        let diff = super::diff(
+            "Linux\nx86_64\n...\ndone\n",
+            "Linux\naarch64\n1\ndone\nextra\n",
+            12,
+        );
+        assert_eq!(
+            diff,
+            "--- expected\n+++ actual\n@@ -12,4 +1,5 @@\n Linux\n-x86_64\n aarch64\n 1\n done\n+extra\n"
+        );
+    }
+}
@@ -0,0 +1,903 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Boolean filter expressions used in code block info strings.
+//!
+//! A filter follows the language tag, e.g. ```` ```sh:git && !sev ````, and
+//! combines contexts and os-release predicates with `&&`, `||`, `!` and
+//! parentheses. `!` binds tightest, then `&&`, then `||`.
+//!
+//! os-release predicates compare a key against a value with `=` or `!=`
+//! (exact string comparison) or with `<`, `<=`, `>` and `>=` (dotted
+//! numeric version comparison, e.g. `VERSION_ID>=20.04`). Fields holding a
+//! space-separated list such as `ID_LIKE` can be tested for membership with
+//! `~=`, and `like=fedora` matches a distribution family: it is true when
+//! `ID` is `fedora` or when `fedora` appears in `ID_LIKE`.
+//!
+//! The legacy `contexts;KEY=VALUE ...` form is still accepted and is mapped
+//! onto the same expression tree: `git,sev;ID=debian ID=fedora` is
+//! equivalent to `(git || sev) && (ID=debian || ID=fedora)`. Only plain
+//! `KEY=VALUE` words are joined this way, with or without the `;`, while
+//! other comparisons must be joined with an explicit `&&` or `||`.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+use crate::json::Json;
+use crate::os_release::Facts;
+
+/// A parsed filter expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Matches when the named context is enabled.
+    Context(String),
+
+    /// Matches when the os-release `key` compares to `value` using `op`.
+    Os { key: String, op: Op, value: String },
+
+    /// Matches when `ID` or any entry of `ID_LIKE` is the given distribution.
+    Like(String),
+
+    /// Matches when the inner filter does not.
+    Not(Box<Filter>),
+
+    /// Matches when both filters match.
+    And(Box<Filter>, Box<Filter>),
+
+    /// Matches when either filter matches.
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// A comparison operator in an os-release predicate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+impl Op {
+    /// Applies the operator to an os-release value.
+    ///
+    /// `=` and `!=` compare strings exactly and `~=` tests whether `rhs` is
+    /// an entry of the space-separated list `lhs`. The ordering operators
+    /// compare dotted numeric versions and fail on values that are not
+    /// versions.
+    fn apply(self, key: &str, lhs: &str, rhs: &str) -> Result<bool> {
+        let ordering = match self {
+            Self::Eq => return Ok(lhs == rhs),
+            Self::Ne => return Ok(lhs != rhs),
+            Self::In => return Ok(list(lhs).any(|entry| entry == rhs)),
+            _ => match (version(lhs), version(rhs)) {
+                (Some(l), Some(r)) => cmp_versions(&l, &r),
+                (None, _) => {
+                    return Err(Error::OsNotAVersion {
+                        key: key.into(),
+                        op: self,
+                        value: lhs.into(),
+                    })
+                }
+                (_, None) => {
+                    return Err(Error::FilterNotAVersion {
+                        key: key.into(),
+                        op: self,
+                        value: rhs.into(),
+                    })
+                }
+            },
+        };
+
+        Ok(match self {
+            Self::Lt => ordering.is_lt(),
+            Self::Le => ordering.is_le(),
+            Self::Gt => ordering.is_gt(),
+            Self::Ge => ordering.is_ge(),
+            Self::Eq | Self::Ne | Self::In => unreachable!(),
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::In => "~=",
+        })
+    }
+}
+
+/// A context or os-release key referenced by a filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    Context(String),
+    Key(String),
+}
+
+/// Lists the contexts and os-release keys referenced by a filter, together
+/// with their byte offsets.
+///
+/// This accepts the same input as [`Filter::parse_info`] and is meant for
+/// diagnostics; on a syntax error the references lexed so far are returned.
+pub fn references(input: &str) -> Vec<(usize, Reference)> {
+    let mut out = Vec::new();
+    let (os, base) = match input.split_once(';') {
+        Some((cx, os)) => {
+            let mut offset = 0;
+            for name in cx.split(',') {
+                let trimmed = name.trim();
+                if !trimmed.is_empty() {
+                    let start = offset + name.len() - name.trim_start().len();
+                    out.push((start, Reference::Context(trimmed.into())));
+                }
+                offset += name.len() + 1;
+            }
+            (os, cx.len() + 1)
+        }
+        None => (input, 0),
+    };
+
+    let tokens = match tokenize(os, base) {
+        Ok(tokens) => tokens,
+        Err(e) => tokenize(&os[..e.offset - base], base).unwrap_or_default(),
+    };
+
+    let mut prev_op = false;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some((offset, token)) = tokens.next() {
+        let next_op = matches!(tokens.peek(), Some((_, Token::Op(..))));
+        if let Token::Word(word) = &token {
+            match (prev_op, next_op) {
+                (false, true) if word != "like" => out.push((offset, Reference::Key(word.clone()))),
+                (false, false) => out.push((offset, Reference::Context(word.clone()))),
+                _ => (),
+            }
+        }
+        prev_op = matches!(token, Token::Op(..));
+    }
+
+    out
+}
+
+/// Formats a set of names as `{a, b}` in sorted order.
+fn set<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    let mut names = names.collect::<Vec<_>>();
+    names.sort_unstable();
+    format!("{{{}}}", names.join(", "))
+}
+
+/// Iterates over the entries of a space-separated os-release list value.
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split_whitespace()
+}
+
+/// Parses a dotted numeric version such as `20.04` into its components.
+fn version(value: &str) -> Option<Vec<u64>> {
+    value.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// Compares two versions component-wise, treating missing components as zero.
+fn cmp_versions(lhs: &[u64], rhs: &[u64]) -> Ordering {
+    let len = lhs.len().max(rhs.len());
+    let get = |v: &[u64], i| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| get(lhs, i).cmp(&get(rhs, i)))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// A syntax error in a filter expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// Byte offset of the error within the filter string.
+    pub offset: usize,
+
+    /// A description of what went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.offset + 1, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+impl Filter {
+    /// Parses the filter part of an info string (everything after `sh:`).
+    ///
+    /// Returns `None` when the filter is empty and therefore matches always.
+    pub fn parse_info(input: &str) -> Result<Option<Self>, SyntaxError> {
+        if let Some((cx, os)) = input.split_once(';') {
+            return Self::parse_legacy(cx, os, cx.len() + 1);
+        }
+
+        if is_legacy_list(input) {
+            return Self::parse_legacy("", input, 0);
+        }
+
+        if input.trim().is_empty() {
+            return Ok(None);
+        }
+
+        Self::parse(input).map(Some)
+    }
+
+    /// Parses a filter expression.
+    pub fn parse(input: &str) -> Result<Self, SyntaxError> {
+        let mut parser = Parser::new(input, 0)?;
+        let filter = parser.expr()?;
+        parser.finish()?;
+        Ok(filter)
+    }
+
+    /// Maps the legacy `contexts;KEY=VALUE ...` form onto an expression.
+    ///
+    /// `base` is the offset of `os` within the original input, so that
+    /// errors point at the right column.
+    fn parse_legacy(cx: &str, os: &str, base: usize) -> Result<Option<Self>, SyntaxError> {
+        let cx = cx
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| Self::Context(c.into()))
+            .reduce(|l, r| Self::Or(l.into(), r.into()));
+
+        // As without contexts, only plain `KEY=VALUE` words form an implicit
+        // OR, and anything else is an expression needing explicit operators.
+        if words(os).nth(1).is_some() && !is_legacy_list(os) {
+            let mut parser = Parser::new(os, base)?;
+            let os = parser.expr()?;
+            parser.finish()?;
+            return Ok(Some(match cx {
+                Some(cx) => Self::And(cx.into(), os.into()),
+                None => os,
+            }));
+        }
+
+        let mut preds = Vec::new();
+        for (offset, word) in words(os) {
+            let mut parser = Parser::new(word, base + offset)?;
+            match parser.predicate()? {
+                Self::Context(..) => return error(base + offset, "expected `KEY=VALUE`"),
+                pred => preds.push(pred),
+            }
+            parser.finish()?;
+        }
+        let os = preds
+            .into_iter()
+            .reduce(|l, r| Self::Or(l.into(), r.into()));
+
+        Ok(match (cx, os) {
+            (Some(cx), Some(os)) => Some(Self::And(cx.into(), os.into())),
+            (cx, os) => cx.or(os),
+        })
+    }
+
+    /// Evaluates the filter against the enabled contexts and os-release facts.
+    ///
+    /// A predicate on a key missing from os-release only matches with `!=`.
+    pub fn eval(&self, cx: &HashSet<String>, os: &Facts) -> Result<bool> {
+        Ok(match self {
+            Self::Context(name) => cx.contains(name),
+            Self::Os { key, op, value } => match os.get(key) {
+                Some(actual) => op.apply(key, actual, value)?,
+                None => *op == Op::Ne,
+            },
+            Self::Like(name) => {
+                os.get("ID").is_some_and(|id| id == name)
+                    || os
+                        .get("ID_LIKE")
+                        .is_some_and(|like| list(like).any(|entry| entry == name))
+            }
+            Self::Not(f) => !f.eval(cx, os)?,
+            Self::And(l, r) => l.eval(cx, os)? && r.eval(cx, os)?,
+            Self::Or(l, r) => l.eval(cx, os)? || r.eval(cx, os)?,
+        })
+    }
+
+    /// Evaluates the filter and describes the clause that decided the result.
+    ///
+    /// Short-circuiting follows `eval`, so the reason names the first clause
+    /// that fixed the verdict, e.g. "ID=fedora did not match ID=debian".
+    pub fn explain(&self, cx: &HashSet<String>, os: &Facts) -> Result<(bool, String)> {
+        if let Some(names) = self.context_set() {
+            let matched = names.iter().find(|n| cx.contains(**n));
+            let reason = match matched {
+                Some(name) => format!(
+                    "context filter {} intersected {} at {}",
+                    set(names.iter().copied()),
+                    set(cx.iter().map(String::as_str)),
+                    name
+                ),
+                None => format!(
+                    "context filter {} did not intersect {}",
+                    set(names.iter().copied()),
+                    set(cx.iter().map(String::as_str))
+                ),
+            };
+            return Ok((matched.is_some(), reason));
+        }
+
+        Ok(match self {
+            Self::Context(..) => unreachable!(),
+            Self::Os { key, .. } | Self::Like(key) => {
+                let matched = self.eval(cx, os)?;
+                let verb = if matched { "matched" } else { "did not match" };
+                let key = if let Self::Like(..) = self { "ID" } else { key };
+                let actual = match os.get(key) {
+                    Some(value) => format!("{}={}", key, Value(value)),
+                    None => format!("unset {}", key),
+                };
+                let mut reason = format!("{} {} {}", self, verb, actual);
+                if let (Self::Like(..), Some(like)) = (self, os.get("ID_LIKE")) {
+                    reason += &format!(" or ID_LIKE={}", Value(like));
+                }
+                (matched, reason)
+            }
+            Self::Not(f) => {
+                let (matched, reason) = f.explain(cx, os)?;
+                (!matched, format!("not: {}", reason))
+            }
+            Self::And(l, r) => match l.explain(cx, os)? {
+                (false, reason) => (false, reason),
+                (true, lr) => match r.explain(cx, os)? {
+                    (false, reason) => (false, reason),
+                    (true, rr) => (true, format!("{} and {}", lr, rr)),
+                },
+            },
+            Self::Or(l, r) => match l.explain(cx, os)? {
+                (true, reason) => (true, reason),
+                (false, lr) => match r.explain(cx, os)? {
+                    (true, reason) => (true, reason),
+                    (false, rr) => (false, format!("{} and {}", lr, rr)),
+                },
+            },
+        })
+    }
+
+    /// Returns the contexts and predicates of this filter, left to right.
+    pub fn leaves(&self) -> Vec<&Self> {
+        match self {
+            Self::Context(..) | Self::Os { .. } | Self::Like(..) => vec![self],
+            Self::Not(inner) => inner.leaves(),
+            Self::And(l, r) | Self::Or(l, r) => {
+                let mut leaves = l.leaves();
+                leaves.extend(r.leaves());
+                leaves
+            }
+        }
+    }
+
+    /// Returns the expression tree of this filter as JSON.
+    ///
+    /// Each node is an object with a single field naming its kind, e.g.
+    /// `{"and":[{"context":"git"},{"key":"ID","op":"=","value":"fedora"}]}`.
+    pub fn to_json(&self) -> Json {
+        match self {
+            Self::Context(name) => Json::object([("context", name.as_str().into())]),
+            Self::Os { key, op, value } => Json::object([
+                ("key", key.as_str().into()),
+                ("op", op.to_string().into()),
+                ("value", value.as_str().into()),
+            ]),
+            Self::Like(name) => Json::object([("like", name.as_str().into())]),
+            Self::Not(inner) => Json::object([("not", inner.to_json())]),
+            Self::And(l, r) => Json::object([("and", Json::Array(vec![l.to_json(), r.to_json()]))]),
+            Self::Or(l, r) => Json::object([("or", Json::Array(vec![l.to_json(), r.to_json()]))]),
+        }
+    }
+
+    /// Returns the context names if this filter is a disjunction of contexts.
+    fn context_set(&self) -> Option<Vec<&str>> {
+        match self {
+            Self::Context(name) => Some(vec![name]),
+            Self::Or(l, r) => {
+                let mut names = l.context_set()?;
+                names.extend(r.context_set()?);
+                Some(names)
+            }
+            _ => None,
+        }
+    }
+
+    /// Binding strength used to decide where `Display` needs parentheses.
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Or(..) => 0,
+            Self::And(..) => 1,
+            Self::Not(..) => 2,
+            Self::Context(..) | Self::Os { .. } | Self::Like(..) => 3,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, min: u8) -> fmt::Result {
+        if self.precedence() < min {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Context(name) => f.write_str(name),
+            Self::Os { key, op, value } => write!(f, "{}{}{}", key, op, Value(value)),
+            Self::Like(name) => write!(f, "like={}", Value(name)),
+            Self::Not(inner) => {
+                f.write_str("!")?;
+                inner.fmt_operand(f, 2)
+            }
+            Self::And(l, r) => {
+                l.fmt_operand(f, 1)?;
+                f.write_str(" && ")?;
+                r.fmt_operand(f, 2)
+            }
+            Self::Or(l, r) => {
+                l.fmt_operand(f, 0)?;
+                f.write_str(" || ")?;
+                r.fmt_operand(f, 1)
+            }
+        }
+    }
+}
+
+/// Displays a value, quoting it when it would not lex as a single word.
+struct Value<'a>(&'a str);
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.0.is_empty() && self.0.chars().all(is_word_char) {
+            f.write_str(self.0)
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
+}
+
+/// Returns true if the input is a legacy whitespace-separated list of two or
+/// more plain `KEY=VALUE` predicates.
+///
+/// Other comparisons, such as `VERSION_ID>=11`, are not accepted here, so that
+/// a list of them is a syntax error asking for an explicit `&&` or `||`.
+fn is_legacy_list(input: &str) -> bool {
+    let words = input.split_whitespace().collect::<Vec<_>>();
+    words.len() > 1
+        && words.iter().all(|w| match w.split_once('=') {
+            Some((key, value)) => {
+                !key.is_empty()
+                    && key.chars().all(is_word_char)
+                    && !value.starts_with('=')
+                    && value.chars().all(is_word_char)
+            }
+            None => false,
+        })
+}
+
+/// Splits the input on whitespace, yielding each word with its byte offset.
+fn words(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    input
+        .split_whitespace()
+        .map(move |w| (w.as_ptr() as usize - input.as_ptr() as usize, w))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '/' | '+' | ':' | '@')
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(Op),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "`{}`", w),
+            Self::Str(s) => write!(f, "{:?}", s),
+            Self::Op(op) => write!(f, "`{}`", op),
+            Self::Not => f.write_str("`!`"),
+            Self::And => f.write_str("`&&`"),
+            Self::Or => f.write_str("`||`"),
+            Self::Open => f.write_str("`(`"),
+            Self::Close => f.write_str("`)`"),
+        }
+    }
+}
+
+fn error<T>(offset: usize, message: impl Into<String>) -> Result<T, SyntaxError> {
+    Err(SyntaxError {
+        offset,
+        message: message.into(),
+    })
+}
+
+/// Splits the input into tokens, each paired with its byte offset.
+fn tokenize(input: &str, base: usize) -> Result<Vec<(usize, Token)>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let offset = base + i;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' => Token::Op(Op::Eq),
+            '!' | '<' | '>' => match (c, chars.next_if(|&(_, n)| n == '=').is_some()) {
+                ('!', false) => Token::Not,
+                ('!', true) => Token::Op(Op::Ne),
+                ('<', false) => Token::Op(Op::Lt),
+                ('<', true) => Token::Op(Op::Le),
+                ('>', false) => Token::Op(Op::Gt),
+                (_, _) => Token::Op(Op::Ge),
+            },
+            '~' => match chars.next_if(|&(_, n)| n == '=') {
+                Some(..) => Token::Op(Op::In),
+                None => return error(offset, "expected `~=`"),
+            },
+            '&' | '|' => match chars.next_if(|&(_, n)| n == c) {
+                Some(..) if c == '&' => Token::And,
+                Some(..) => Token::Or,
+                None => return error(offset, format!("expected `{0}{0}`", c)),
+            },
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => value.push(c),
+                            None => return error(offset, "unterminated string"),
+                        },
+                        Some((_, c)) => value.push(c),
+                        None => return error(offset, "unterminated string"),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if is_word_char(c) => {
+                let mut word = String::from(c);
+                while let Some((_, c)) = chars.next_if(|&(_, c)| is_word_char(c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return error(offset, format!("unexpected character `{}`", c)),
+        };
+        tokens.push((offset, token));
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the filter grammar:
+///
+/// ```text
+/// expr    := and ( "||" and )*
+/// and     := unary ( "&&" unary )*
+/// unary   := "!" unary | primary
+/// primary := "(" expr ")" | WORD [ op value ]
+/// op      := "=" | "!=" | "<" | "<=" | ">" | ">=" | "~="
+/// value   := WORD | STRING
+/// ```
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<(usize, Token)>>,
+    end: usize,
+}
+
+impl Parser {
+    fn new(input: &str, base: usize) -> Result<Self, SyntaxError> {
+        Ok(Self {
+            tokens: tokenize(input, base)?.into_iter().peekable(),
+            end: base + input.len(),
+        })
+    }
+
+    fn next_if(&mut self, token: &Token) -> bool {
+        self.tokens.next_if(|(_, t)| t == token).is_some()
+    }
+
+    fn finish(&mut self) -> Result<(), SyntaxError> {
+        match self.tokens.next() {
+            None => Ok(()),
+            // Another term, e.g. in `ID=ubuntu VERSION_ID>=11`.
+            Some((offset, token @ (Token::Word(..) | Token::Not | Token::Open))) => {
+                error(offset, format!("expected `&&` or `||` before {}", token))
+            }
+            Some((offset, token)) => error(offset, format!("unexpected {}", token)),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Filter, SyntaxError> {
+        let mut lhs = self.and()?;
+        while self.next_if(&Token::Or) {
+            lhs = Filter::Or(lhs.into(), self.and()?.into());
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Filter, SyntaxError> {
+        let mut lhs = self.unary()?;
+        while self.next_if(&Token::And) {
+            lhs = Filter::And(lhs.into(), self.unary()?.into());
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Filter, SyntaxError> {
+        if self.next_if(&Token::Not) {
+            return Ok(Filter::Not(self.unary()?.into()));
+        }
+
+        if let Some((offset, _)) = self.tokens.next_if(|(_, t)| *t == Token::Open) {
+            let inner = self.expr()?;
+            if !self.next_if(&Token::Close) {
+                return match self.tokens.next() {
+                    Some((o, t)) => error(o, format!("expected `)`, found {}", t)),
+                    None => error(offset, "unclosed `(`"),
+                };
+            }
+            return Ok(inner);
+        }
+
+        self.predicate()
+    }
+
+    /// Parses a single context name or `KEY<op>VALUE` predicate.
+    fn predicate(&mut self) -> Result<Filter, SyntaxError> {
+        let name = match self.tokens.next() {
+            Some((_, Token::Word(name))) => name,
+            Some((offset, token)) => {
+                return error(
+                    offset,
+                    format!("expected context or predicate, found {}", token),
+                )
+            }
+            None => return error(self.end, "expected context or predicate"),
+        };
+
+        let (offset, op) = match self.tokens.next_if(|(_, t)| matches!(t, Token::Op(..))) {
+            Some((offset, Token::Op(op))) => (offset, op),
+            _ => return Ok(Filter::Context(name)),
+        };
+
+        match self.tokens.next() {
+            Some((_, Token::Word(value) | Token::Str(value))) if name == "like" => match op {
+                Op::Eq => Ok(Filter::Like(value)),
+                Op::Ne => Ok(Filter::Not(Filter::Like(value).into())),
+                _ => error(offset, format!("`like` does not support `{}`", op)),
+            },
+            Some((_, Token::Word(value) | Token::Str(value))) => Ok(Filter::Os {
+                key: name,
+                op,
+                value,
+            }),
+            Some((offset, token)) => error(offset, format!("expected value, found {}", token)),
+            None => error(self.end, format!("expected value for `{}`", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str) -> String {
+        Filter::parse_info(input).unwrap().unwrap().to_string()
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(parse("git && !sev"), "git && !sev");
+        assert_eq!(parse("a || b && c"), "a || b && c");
+        assert_eq!(parse("(a || b) && c"), "(a || b) && c");
+        assert_eq!(parse("!(a&&b)"), "!(a && b)");
+        assert_eq!(
+            parse("(ID=debian && VERSION_ID=11) || ID=ubuntu"),
+            "ID=debian && VERSION_ID=11 || ID=ubuntu"
+        );
+        assert_eq!(
+            parse(r#"NAME="Debian GNU/Linux""#),
+            r#"NAME="Debian GNU/Linux""#
+        );
+    }
+
+    #[test]
+    fn legacy() {
+        assert_eq!(Filter::parse_info("").unwrap(), None);
+        assert_eq!(Filter::parse_info(";").unwrap(), None);
+        assert_eq!(parse("git,sev;"), "git || sev");
+        assert_eq!(parse("ID=fedora"), "ID=fedora");
+        assert_eq!(
+            parse("ID=debian ID_LIKE=debian"),
+            "ID=debian || ID_LIKE=debian"
+        );
+        assert_eq!(
+            parse("git; ID=debian ID=fedora"),
+            "git && (ID=debian || ID=fedora)"
+        );
+        assert_eq!(parse("git; VERSION_ID>=20.04"), "git && VERSION_ID>=20.04");
+        assert_eq!(
+            parse("git; VERSION_ID>=20.04 || ID!=ubuntu"),
+            "git && (VERSION_ID>=20.04 || ID!=ubuntu)"
+        );
+        for (input, offset, message) in [
+            (
+                ";ID!=arch ID!=debian",
+                10,
+                "expected `&&` or `||` before `ID`",
+            ),
+            (
+                "git; ID=ubuntu VERSION_ID>=11",
+                15,
+                "expected `&&` or `||` before `VERSION_ID`",
+            ),
+            (
+                "ID=ubuntu VERSION_ID>=11",
+                10,
+                "expected `&&` or `||` before `VERSION_ID`",
+            ),
+            (
+                "ID!=arch ID!=debian",
+                9,
+                "expected `&&` or `||` before `ID`",
+            ),
+        ] {
+            let err = Filter::parse_info(input).unwrap_err();
+            assert_eq!((err.offset, err.message.as_str()), (offset, message));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |input| Filter::parse_info(input).unwrap_err();
+
+        assert_eq!(err("git &&").offset, 6);
+        assert_eq!(err("git & sev").offset, 4);
+        assert_eq!(err("(git || sev").offset, 0);
+        assert_eq!(err("git sev").offset, 4);
+        assert_eq!(err("ID=").offset, 3);
+        assert_eq!(err("ID>=>").offset, 4);
+        assert_eq!(err("like>=38").offset, 4);
+        assert_eq!(err("ID~fedora").offset, 2);
+        assert_eq!(err("git;ID=debian ID").offset, 14);
+        assert_eq!(err("ID=\"x").message, "unterminated string");
+    }
+
+    #[test]
+    fn eval() {
+        let cx = ["git".to_string()].into_iter().collect();
+        let os = [("ID".to_string(), "debian".to_string())]
+            .into_iter()
+            .collect();
+        let eval = |input| Filter::parse(input).unwrap().eval(&cx, &os).unwrap();
+
+        assert!(eval("git && !sev"));
+        assert!(!eval("git && sev"));
+        assert!(eval("sev || ID=debian"));
+        assert!(!eval("!(ID=debian)"));
+        assert!(eval("ID!=fedora"));
+        assert!(eval("VERSION_ID!=11"));
+        assert!(!eval("VERSION_ID>=11"));
+    }
+
+    #[test]
+    fn explain() {
+        let cx = ["git".to_string()].into_iter().collect();
+        let os = [("ID", "debian"), ("ID_LIKE", "ubuntu mint")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let explain = |input| {
+            Filter::parse_info(input)
+                .unwrap()
+                .unwrap()
+                .explain(&cx, &os)
+                .unwrap()
+        };
+
+        assert_eq!(
+            explain("notgit; ID=debian"),
+            (
+                false,
+                "context filter {notgit} did not intersect {git}".to_string()
+            )
+        );
+        assert_eq!(
+            explain("sev,git;"),
+            (
+                true,
+                "context filter {git, sev} intersected {git} at git".to_string()
+            )
+        );
+        assert_eq!(
+            explain("git && ID=fedora"),
+            (false, "ID=fedora did not match ID=debian".to_string())
+        );
+        assert_eq!(
+            explain("!(VERSION_ID>=11)"),
+            (
+                true,
+                "not: VERSION_ID>=11 did not match unset VERSION_ID".to_string()
+            )
+        );
+        assert_eq!(
+            explain("like=fedora"),
+            (
+                false,
+                "like=fedora did not match ID=debian or ID_LIKE=\"ubuntu mint\"".to_string()
+            )
+        );
+        assert_eq!(
+            explain("ID=fedora || ID=debian"),
+            (true, "ID=debian matched ID=debian".to_string())
+        );
+    }
+
+    #[test]
+    fn versions() {
+        let cx = HashSet::new();
+        let eval = |version: &str, input| {
+            let os = [("VERSION_ID".to_string(), version.to_string())]
+                .into_iter()
+                .collect();
+            Filter::parse(input).unwrap().eval(&cx, &os)
+        };
+
+        assert!(eval("38", "VERSION_ID>=38").unwrap());
+        assert!(eval("39", "VERSION_ID>38").unwrap());
+        assert!(!eval("37", "VERSION_ID>=38").unwrap());
+        assert!(eval("22.04", "VERSION_ID>=20.04").unwrap());
+        assert!(eval("20.10", "VERSION_ID>20.4").unwrap());
+        assert!(eval("9", "VERSION_ID<9.1").unwrap());
+        assert!(eval("9.0", "VERSION_ID<=9").unwrap());
+        assert!(eval("20.04", "VERSION_ID!=22.04").unwrap());
+        assert!(eval("rolling", "VERSION_ID>=1").is_err());
+        assert!(eval("38", "VERSION_ID>=rawhide").is_err());
+    }
+
+    #[test]
+    fn like() {
+        let cx = HashSet::new();
+        let eval = |id: &str, like: Option<&str>, input| {
+            let os = [("ID", Some(id)), ("ID_LIKE", like)]
+                .into_iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v?.to_string())))
+                .collect();
+            Filter::parse(input).unwrap().eval(&cx, &os).unwrap()
+        };
+
+        let rocky = Some("rhel centos fedora");
+        assert!(eval("rocky", rocky, "like=fedora"));
+        assert!(eval("rocky", rocky, "like=rocky"));
+        assert!(eval("rocky", rocky, "ID_LIKE~=centos"));
+        assert!(!eval("rocky", rocky, "ID_LIKE~=cent"));
+        assert!(!eval("rocky", rocky, "like=debian"));
+        assert!(eval("rocky", rocky, "like!=debian"));
+        assert!(eval("debian", None, "like=debian"));
+        assert!(!eval("debian", None, "ID_LIKE~=debian"));
+        assert!(eval("ubuntu", Some("debian"), "like=debian"));
+    }
+}
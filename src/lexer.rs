@@ -0,0 +1,349 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of shell input that continues onto the next line, and quoting
+//! of words for the shell.
+//!
+//! This is not a full shell parser. It only tracks as much of the POSIX shell
+//! grammar as is needed to tell whether a line completes a command: quotes,
+//! backslash continuations, here-documents, groups and compound commands.
+
+use std::collections::VecDeque;
+
+/// Tracks whether the shell input seen so far forms complete commands.
+#[derive(Clone, Debug, Default)]
+pub struct Continuation {
+    /// The open quotes, groups and compound commands, innermost last.
+    open: Vec<&'static str>,
+
+    /// The delimiters of here-documents whose bodies have not ended yet, and
+    /// whether leading tabs are stripped before comparing against them.
+    heredocs: VecDeque<(String, bool)>,
+
+    /// Whether the last line ended in a backslash.
+    escaped: bool,
+
+    /// Whether the next word is in command position, where reserved words
+    /// such as `if` and `}` are recognized.
+    command: bool,
+
+    /// Whether the next word is the name following the `function` keyword,
+    /// after which the function body is in command position.
+    function: bool,
+
+    /// Whether any line so far contained a comment.
+    commented: bool,
+}
+
+impl Continuation {
+    /// Creates a tracker at the start of a command.
+    pub fn new() -> Self {
+        Self {
+            command: true,
+            ..Self::default()
+        }
+    }
+
+    /// Returns whether the input so far ends at the end of a command.
+    pub fn is_complete(&self) -> bool {
+        self.open.is_empty() && self.heredocs.is_empty() && !self.escaped
+    }
+
+    /// Returns whether the input so far contains a comment, which would hide
+    /// the rest of the line if more commands were appended to it.
+    pub fn has_comment(&self) -> bool {
+        self.commented
+    }
+
+    /// Feeds one line of input, without its line terminator.
+    pub fn feed(&mut self, line: &str) {
+        if let Some((delimiter, strip)) = self.heredocs.front() {
+            let body = if *strip {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if body == delimiter {
+                self.heredocs.pop_front();
+            }
+            return;
+        }
+
+        let chars = line.chars().collect::<Vec<_>>();
+        let mut word = String::new();
+        let mut i = 0;
+        self.escaped = false;
+
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            i += 1;
+
+            match (self.open.last().copied(), c) {
+                (Some("'"), '\'') => self.close("'"),
+                (Some("'"), _) => (),
+
+                (Some("\""), '"') => self.close("\""),
+                (Some("\""), '\\') => {
+                    self.escaped = next.is_none();
+                    i += 1;
+                }
+                (Some("\""), '$') => i += self.expansion(&chars[i..]),
+                (Some("\""), _) => (),
+
+                (_, '\\') => {
+                    self.escaped = next.is_none();
+                    word.push(c);
+                    word.extend(next);
+                    i += 1;
+                }
+                (_, '\'') => {
+                    self.open.push("'");
+                    word.push(c);
+                }
+                (_, '"') => {
+                    self.open.push("\"");
+                    word.push(c);
+                }
+                (_, '$') => {
+                    i += self.expansion(&chars[i..]);
+                    word.push(c);
+                }
+                (Some("${"), '}') => self.close("${"),
+                (_, '#') if word.is_empty() => {
+                    self.commented = true;
+                    break;
+                }
+
+                // In arithmetic, `<<` is a shift rather than a here-document.
+                (Some("(("), '<') if next == Some('<') => i += 1,
+                (_, '<') if next == Some('<') => {
+                    self.word(&mut word);
+                    i = match chars.get(i + 1) {
+                        // A here-string is a plain word.
+                        Some('<') => i + 2,
+                        _ => self.heredoc(&chars, i + 1),
+                    };
+                }
+
+                (_, ';' | '&' | '|') => {
+                    self.word(&mut word);
+                    self.command = true;
+                }
+                (_, '(') if next == Some('(') && self.command && word.is_empty() => {
+                    self.open.push("((");
+                    self.command = false;
+                    i += 1;
+                }
+                (_, '(') => {
+                    self.word(&mut word);
+                    self.open.push("(");
+                    self.command = true;
+                }
+                (Some("(("), ')') if next == Some(')') => {
+                    self.word(&mut word);
+                    self.open.pop();
+                    i += 1;
+                }
+                (_, ')') => {
+                    self.word(&mut word);
+                    // In a case item, `)` ends the pattern instead of a group.
+                    if self.open.last() == Some(&"(") {
+                        self.open.pop();
+                    }
+                    self.command = true;
+                }
+                (_, '<' | '>') => self.word(&mut word),
+
+                (_, c) if c.is_whitespace() => self.word(&mut word),
+                (_, c) => word.push(c),
+            }
+        }
+
+        self.word(&mut word);
+        if !self.escaped && !matches!(self.open.last(), Some(&"'" | &"\"")) {
+            self.command = true;
+        }
+    }
+
+    /// Handles a `$` followed by `rest`, returning how many characters of
+    /// the expansion were consumed after the `$`.
+    fn expansion(&mut self, rest: &[char]) -> usize {
+        match rest {
+            ['(', '(', ..] => {
+                self.open.push("((");
+                self.command = false;
+                2
+            }
+            ['(', ..] => {
+                self.open.push("(");
+                self.command = true;
+                1
+            }
+            ['{', ..] => {
+                self.open.push("${");
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Closes the innermost quote, group or compound command if it is `open`.
+    fn close(&mut self, open: &str) {
+        if self.open.last() == Some(&open) {
+            self.open.pop();
+        }
+    }
+
+    /// Finishes a word, handling it as a reserved word in command position.
+    fn word(&mut self, word: &mut String) {
+        if word.is_empty() {
+            return;
+        }
+
+        if self.function {
+            self.function = false;
+            self.command = true;
+        } else if self.command {
+            match word.as_str() {
+                "if" => self.open.push("if"),
+                "do" => self.open.push("do"),
+                "{" => self.open.push("{"),
+                "fi" => self.close("if"),
+                "esac" => self.close("case"),
+                "done" => self.close("do"),
+                "}" => self.close("{"),
+                "then" | "else" | "elif" | "while" | "until" | "!" => (),
+                "case" => {
+                    self.open.push("case");
+                    self.command = false;
+                }
+                "function" => {
+                    self.function = true;
+                    self.command = false;
+                }
+                _ => self.command = false,
+            }
+        }
+
+        word.clear();
+    }
+
+    /// Records the delimiter of a here-document starting after the `<<` that
+    /// ends before `i`, returning the index following the delimiter.
+    fn heredoc(&mut self, chars: &[char], mut i: usize) -> usize {
+        let strip = chars.get(i) == Some(&'-');
+        if strip {
+            i += 1;
+        }
+        while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+            i += 1;
+        }
+
+        let mut delimiter = String::new();
+        while let Some(&c) = chars.get(i) {
+            if c.is_whitespace() || ";&|<>()".contains(c) {
+                break;
+            }
+            if !"'\"\\".contains(c) {
+                delimiter.push(c);
+            }
+            i += 1;
+        }
+
+        if !delimiter.is_empty() {
+            self.heredocs.push_back((delimiter, strip));
+        }
+        i
+    }
+}
+
+/// Quotes a word for the shell if it contains anything but safe characters.
+pub(crate) fn quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./+:@,=".contains(c))
+    {
+        word.into()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns whether each line completes the input fed so far.
+    fn complete(lines: &str) -> Vec<bool> {
+        let mut state = Continuation::new();
+        lines
+            .lines()
+            .map(|line| {
+                state.feed(line);
+                state.is_complete()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn continuation() {
+        assert_eq!(complete("echo a"), [true]);
+        assert_eq!(complete("echo a \\\n  b"), [false, true]);
+        assert_eq!(complete("echo 'a\nb' \"c\\\"\nd\""), [false, false, true]);
+        assert_eq!(complete("echo \"$(date \\\n)\""), [false, true]);
+        assert_eq!(complete("echo ${HOME} # it's {"), [true]);
+        assert_eq!(complete("echo a\\'b"), [true]);
+    }
+
+    #[test]
+    fn comment() {
+        let commented = |lines: &str| {
+            let mut state = Continuation::new();
+            lines.lines().for_each(|line| state.feed(line));
+            state.has_comment()
+        };
+        assert!(commented("apt-get update # refresh"));
+        assert!(commented("echo a \\\n  b; # c"));
+        assert!(!commented("echo '#' \"#\" a#b \\# ${#x}"));
+        assert!(!commented("cat <<EOF\n# body\nEOF"));
+    }
+
+    #[test]
+    fn heredoc() {
+        assert_eq!(
+            complete("cat <<EOF >x; cat <<-'END'\nline\nEOF\n\tEND"),
+            [false, false, false, true]
+        );
+        assert_eq!(complete("cat <<< 'here string'"), [true]);
+    }
+
+    #[test]
+    fn compound() {
+        assert_eq!(complete("f() {\n  echo done\n}"), [false, false, true]);
+        assert_eq!(
+            complete("if true; then\n  echo if\nfi"),
+            [false, false, true]
+        );
+        assert_eq!(
+            complete("for x in a b; do\n  echo $x\ndone"),
+            [false, false, true]
+        );
+        assert_eq!(
+            complete("case $x in\n  a) echo a;;\n  (b) echo b;;\nesac"),
+            [false, false, false, true]
+        );
+        assert_eq!(complete("(cd /tmp\n  ls)"), [false, true]);
+        assert_eq!(complete("echo { done }"), [true]);
+        assert_eq!(
+            complete("function f {\n  echo done\n}"),
+            [false, false, true]
+        );
+        assert_eq!(complete("function f() {\n  :\n}"), [false, false, true]);
+        assert_eq!(complete("echo $((1 << 2))"), [true]);
+        assert_eq!(complete("echo \"$(( (1 + 2) << 2 ))\""), [true]);
+        assert_eq!(complete("(( x <<= 1 ))\necho done"), [true, true]);
+        assert_eq!(complete("echo $((1 +\n  2))"), [false, true]);
+    }
+}
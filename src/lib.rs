@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Extraction of shell commands from the code blocks of markdown documents.
+//!
+//! Code blocks tagged `sh`, `console` or `shell-session` may carry a filter
+//! after the language tag, e.g. ```` ```sh:git && ID=fedora ````, which
+//! selects them by the enabled contexts and the [`Facts`] of an os-release
+//! file. [`markdown::blocks`] parses a document into [`Block`]s, each with
+//! its [`Filter`] and [`Command`]s, and [`select`] yields the blocks
+//! selected for a set of contexts and facts. Each block also records the
+//! headings it is nested in, which a [`Section`] pattern can match.
+
+use std::collections::HashSet;
+use std::io::Read;
+
+pub mod attributes;
+pub mod command;
+pub mod dependencies;
+pub mod distro;
+pub mod dockerfile;
+mod error;
+mod expect;
+pub mod filter;
+pub mod json;
+mod lexer;
+pub mod lint;
+pub mod markdown;
+pub mod matrix;
+pub mod os_release;
+pub mod run;
+pub mod script;
+pub mod section;
+pub mod source;
+pub mod workflow;
+
+pub use attributes::Attributes;
+pub use command::{Command, Lang, Prompts};
+pub use error::{Error, Result};
+pub use filter::Filter;
+pub use markdown::Block;
+pub use os_release::Facts;
+pub use section::Section;
+pub use source::Source;
+
+/// Returns an iterator over the blocks whose filters match the contexts and
+/// os-release facts, in document order.
+///
+/// Evaluating a filter fails when a version comparison meets a value that
+/// is not a version, which is yielded as an error for that block.
+pub fn select<'a>(
+    blocks: &'a [Block],
+    cx: &'a HashSet<String>,
+    os: &'a Facts,
+) -> impl 'a + Iterator<Item = Result<&'a Block>> {
+    blocks
+        .iter()
+        .filter_map(move |block| match block.include(cx, os) {
+            Ok(true) => Some(Ok(block)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        })
+}
+
+/// Returns an iterator over the command lines in code blocks based on OS filters.
+///
+/// Each item holds the newline-terminated commands of one selected block.
+pub fn filter_markdown<'a>(
+    cx: &'a HashSet<String>,
+    os: impl Read,
+    md: &'a str,
+) -> Result<impl 'a + Iterator<Item = String>> {
+    // Read the distribution variables.
+    let os_release = os_release::read(os)?;
+
+    let blocks = markdown::blocks(md, &Prompts::default())?;
+    let texts = select(&blocks, cx, &os_release)
+        .map(|block| block.map(Block::script))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(texts.into_iter())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select() {
+        let md = "```sh:ID=fedora\necho a\n```\n\n```sh:VERSION_ID>=40\necho b\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let cx = HashSet::new();
+
+        let mut os = Facts::new();
+        os.insert("ID".into(), "fedora".into());
+        os.insert("VERSION_ID".into(), "rawhide".into());
+        let mut selected = super::select(&blocks, &cx, &os);
+        assert_eq!(selected.next().unwrap().unwrap().line, 1);
+        match selected.next() {
+            Some(Err(Error::Block { line: 5, error })) => {
+                assert!(matches!(*error, Error::OsNotAVersion { .. }))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        drop(selected);
+        os.insert("VERSION_ID".into(), "40".into());
+        let err = Filter::parse("VERSION_ID>=stable")
+            .unwrap()
+            .eval(&cx, &os)
+            .unwrap_err();
+        assert!(matches!(err, Error::FilterNotAVersion { .. }));
+
+        let err = markdown::blocks("```sh:ID=\n```\n", &Prompts::default()).unwrap_err();
+        assert!(matches!(err, Error::InvalidFilter { line: 1, .. }));
+    }
+}
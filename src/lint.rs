@@ -0,0 +1,232 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of code block info strings and directives.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::command::Lang;
+use crate::filter::{self, Filter, Reference};
+use crate::{attributes, distro, markdown, os_release};
+
+/// A problem found in a code block's info string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The 1-based line of the problem.
+    pub line: usize,
+
+    /// The 1-based column of the problem.
+    pub column: usize,
+
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Checks the info strings of all code blocks and the filters of all
+/// directives in a markdown document.
+///
+/// Besides syntax errors this reports empty context lists, os-release keys
+/// that are neither in the specification nor in any built-in distro, contexts
+/// that look like a key missing its value, filters on languages whose blocks
+/// are never extracted and directives that are not followed by a heading.
+pub fn lint(md: &str) -> Vec<Diagnostic> {
+    let known = known_keys();
+    let mut out = Vec::new();
+
+    for fence in markdown::fences(md) {
+        let (lang, param) = match fence.info.split_once(':') {
+            Some(split) => split,
+            None => continue,
+        };
+
+        let line = fence.line;
+        let base = fence.column + lang.len() + 1;
+        let mut report = |column: usize, message: String| {
+            out.push(Diagnostic {
+                line,
+                column,
+                message,
+            })
+        };
+
+        if Lang::from_tag(lang).is_none() {
+            report(
+                fence.column,
+                format!(
+                    "unsupported language `{}`: only `sh`, `console` and `shell-session` blocks are extracted",
+                    lang
+                ),
+            );
+            continue;
+        }
+
+        if !param.contains(';') && param.trim().is_empty() {
+            report(base, format!("empty filter after `{}:`", lang));
+        }
+
+        match attributes::split(param) {
+            Ok((_, filter)) => check(&filter, base, &known, &mut report),
+            Err(e) => report(base + e.offset, e.message),
+        }
+    }
+
+    for directive in markdown::directives(md) {
+        let line = directive.line;
+        let mut report = |column: usize, message: String| {
+            out.push(Diagnostic {
+                line,
+                column,
+                message,
+            })
+        };
+
+        if directive.stray {
+            report(
+                directive.column,
+                "doctest directive must be followed by a heading".into(),
+            );
+        }
+        if directive.filter.trim().is_empty() {
+            report(directive.column, "empty filter in doctest directive".into());
+        }
+        check(&directive.filter, directive.column, &known, &mut report);
+    }
+
+    out.sort_by_key(|d| (d.line, d.column));
+    out
+}
+
+/// Checks a filter starting at `column`, reporting problems through `report`.
+fn check(
+    filter: &str,
+    column: usize,
+    known: &HashSet<String>,
+    report: &mut impl FnMut(usize, String),
+) {
+    if let Some((cx, _)) = filter.split_once(';') {
+        if !cx.is_empty() && cx.split(',').any(|c| c.trim().is_empty()) {
+            report(column, "empty entry in context list".into());
+        }
+    }
+
+    if let Err(e) = Filter::parse_info(filter) {
+        report(column + e.offset, e.message);
+    }
+
+    for (offset, reference) in filter::references(filter) {
+        match reference {
+            Reference::Key(key) if !known.contains(key.as_str()) => {
+                report(column + offset, format!("unknown os-release key `{}`", key))
+            }
+            Reference::Context(name) if known.contains(name.as_str()) => report(
+                column + offset,
+                format!(
+                    "context `{}` looks like an os-release key; did you mean `{}=<value>`?",
+                    name, name
+                ),
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// Returns the os-release keys from the specification and built-in distros.
+fn known_keys() -> HashSet<String> {
+    let mut keys = os_release::KEYS
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<HashSet<_>>();
+    for name in distro::names() {
+        if let Ok(os) = distro::get(name).and_then(os_release::parse) {
+            keys.extend(os.into_keys());
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn lint() {
+        let md = r#"# Install
+
+```sh:ID
+echo a
+```
+
+```sh:git,,sev;ID=debian
+echo b
+```
+
+```sh:ID=fedora && VERSOIN_ID>=38
+echo c
+```
+
+```sh:git &&
+echo d
+```
+
+```bash:ID=debian
+echo e
+```
+
+```sh:
+echo f
+```
+
+```sh:like=debian || ID_LIKE~=rhel || UBUNTU_CODENAME=jammy
+echo ok
+```
+
+```sh:ID=debian cwd=build env=CC=clang
+echo ok
+```
+
+```sh:git cwd=
+echo g
+```
+
+```rust
+fn main() {}
+```
+
+<!-- doctest: ID= -->
+## Fedora
+
+<!-- doctest: VERSOIN_ID>=38 || sev -->
+<!-- doctest: -->
+## SEV
+
+<!-- doctest: git -->
+Not a heading.
+"#;
+
+        let diags = super::lint(md)
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diags,
+            [
+                "3:7: context `ID` looks like an os-release key; did you mean `ID=<value>`?",
+                "7:7: empty entry in context list",
+                "11:20: unknown os-release key `VERSOIN_ID`",
+                "15:13: expected context or predicate",
+                "19:4: unsupported language `bash`: only `sh`, `console` and `shell-session` blocks are extracted",
+                "23:7: empty filter after `sh:`",
+                "35:15: expected a directory after `cwd=`",
+                "43:18: expected value for `ID`",
+                "46:15: unknown os-release key `VERSOIN_ID`",
+                "47:14: empty filter in doctest directive",
+                "50:14: doctest directive must be followed by a heading",
+            ]
+        );
+    }
+}
@@ -2,83 +2,102 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
-use std::ops::Deref;
-
-use anyhow::{anyhow, Result};
-use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
-use regex::Regex;
-
-trait CodeBlockKindExt {
-    /// Determines whether this code block should be included in output.
-    ///
-    /// This is based on matching KEY=VALUE filters from `/etc/os-release`.
-    fn include(&self, cx: &HashSet<String>, os: &HashMap<String, String>) -> bool;
-}
 
-impl CodeBlockKindExt for CodeBlockKind<'_> {
-    fn include(&self, cx: &HashSet<String>, os: &HashMap<String, String>) -> bool {
-        let param = match self {
-            Self::Fenced(k) => match k.split_once(':') {
-                Some(("sh", param)) => param,
-                _ => return k.deref() == "sh", // Include ```sh blocks
-            },
-            _ => return false,
-        };
-        let (c, o) = param.split_once(';').unwrap_or(("", param));
-        if !c.is_empty()
-            && c.split(',')
-                .map(Into::into)
-                .collect::<HashSet<_>>()
-                .intersection(cx)
-                .next()
-                .is_none()
-        {
-            return false;
+use anyhow::{bail, Result};
+use doctest::{dependencies, distro, dockerfile, lint, markdown, matrix, run, script, workflow};
+use doctest::{Block, Facts, Section, Source};
+
+mod cli;
+
+use cli::{Args, Format, Mode, USAGE};
+
+#[cfg(test)]
+use doctest::filter_markdown;
+
+/// Fails if two combinations would be written to the same file, since
+/// distinct sources or contexts may sanitize to the same file name.
+fn unique_file_names(matrix: &[matrix::Combination<'_>]) -> Result<()> {
+    let describe = |c: &matrix::Combination<'_>| {
+        let cx = c.contexts.iter().cloned().collect::<Vec<_>>();
+        format!("`{}` with contexts `{}`", c.source, cx.join(","))
+    };
+
+    let mut names = HashMap::new();
+    for combination in matrix {
+        if let Some(other) = names.insert(combination.file_name(), combination) {
+            bail!(
+                "{} and {} would both be written to {}",
+                describe(other),
+                describe(combination),
+                combination.file_name()
+            );
         }
-        o.is_empty()
-            || o.split_whitespace()
-                .map(|x| x.split_once("=").unwrap())
-                .any(|(k, v)| os.get(k).map(String::as_str) == Some(v))
     }
+    Ok(())
 }
 
-/// Returns an iterator over the command lines in code blocks based on OS filters.
-fn filter_markdown<'a>(
-    cx: &'a HashSet<String>,
-    os: impl Read,
-    md: &'a str,
-) -> Result<impl 'a + Iterator<Item = String>> {
-    // Read the distribution variables.
-    let os_release = BufReader::new(os)
-        .lines()
-        .map(|r| match r {
-            Ok(line) => line
-                .split_once('=')
-                .map(|(k, v)| (k.into(), v.into()))
-                .ok_or(anyhow!("invalid os-release line: {}", line)),
-            Err(e) => Err(anyhow!(e)),
-        })
-        .collect::<Result<HashMap<_, _>>>()
-        .map_err(|e| anyhow!("failed to read os-release: {}", e))?;
-
-    // Filter the command blocks using the filters.
-    let mut dump = false;
-    Ok(Parser::new(md).filter_map(move |event| match event {
-        Event::Start(Tag::CodeBlock(block)) if block.include(cx, &os_release) => {
-            dump = true;
-            None
-        }
+/// Returns why `--section` or `--exclude-section` leaves out a block, if
+/// either does.
+fn outside(args: &Args, block: &Block) -> Option<String> {
+    let within = |section: &&Section| section.matches(&block.headings);
+    if let Some(section) = args.exclude_sections.iter().find(within) {
+        return Some(format!("in excluded section \"{}\"", section));
+    }
+    if !args.sections.is_empty() && !args.sections.iter().any(|s| within(&s)) {
+        return Some("outside the selected sections".into());
+    }
+    None
+}
 
-        Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(..))) => {
-            dump = false;
-            None
+/// Returns the blocks in the selected sections whose filters match the
+/// contexts and os-release.
+///
+/// If `--only` names any blocks, just those and the blocks they need are
+/// returned, ordered by their dependencies.
+fn select<'a>(
+    args: &Args,
+    blocks: &'a [Block],
+    cx: &HashSet<String>,
+    os: &Facts,
+) -> Result<Vec<&'a Block>> {
+    let mut selected = Vec::new();
+    for block in blocks {
+        if outside(args, block).is_none() && block.include(cx, os)? {
+            selected.push(block);
         }
+    }
 
-        Event::Text(text) if dump => Some(text.to_string()),
-        _ => None,
-    }))
+    match args.only.is_empty() {
+        true => Ok(selected),
+        false => Ok(dependencies::resolve(&selected, &args.only)?),
+    }
+}
+
+/// Prints the verdict and deciding clause of every block to stderr.
+fn explain(args: &Args, blocks: &[Block], cx: &HashSet<String>, os: &Facts) -> Result<()> {
+    for block in blocks {
+        let (included, reason) = match outside(args, block) {
+            Some(reason) => (false, reason),
+            None => block.explain(cx, os)?,
+        };
+        eprintln!(
+            "{}:{}: {}`{}`: {}: {}{}",
+            args.markdown.display(),
+            block.line,
+            match block.heading() {
+                Some(heading) => format!("in \"{}\": ", heading),
+                None => String::new(),
+            },
+            block.info,
+            if included { "included" } else { "excluded" },
+            reason,
+            match (&block.filter, block.inherited) {
+                (Some(filter), true) => format!(" (effective filter: `{}`)", filter),
+                _ => String::new(),
+            }
+        );
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -86,24 +105,139 @@ fn main() -> Result<()> {
 
     let cmd = args.next().unwrap();
 
-    let (md, os) = match (args.next(), args.next()) {
-        (Some(md), Some(os)) => (std::fs::read_to_string(md)?, os),
-        _ => {
-            eprintln!("Usage: {} <markdown> <os-release> [<context>]", cmd);
+    let mut args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE.replace("{cmd}", &cmd));
             std::process::exit(1);
         }
     };
 
-    let cx = args
-        .next()
-        .map(|s| s.split(',').map(Into::into).collect())
-        .unwrap_or_default();
-    let re = Regex::new(r"^\s*[\$|#]\s*(?P<command>.+?)\s*").unwrap();
-    for cmd in filter_markdown(&cx, File::open(os)?, &md)? {
-        for line in cmd.lines() {
-            let cleaned_line = re.replace_all(line, "$command").to_string();
-            if cleaned_line.len() > 1 {
-                println!("{}", cleaned_line);
+    let md = std::fs::read_to_string(&args.markdown)?;
+    let prompts = args.prompts();
+
+    match args.mode {
+        Mode::Lint => {
+            let diagnostics = lint::lint(&md);
+            for diag in &diagnostics {
+                eprintln!("{}:{}", args.markdown.display(), diag);
+            }
+            if !diagnostics.is_empty() {
+                bail!("{} problem(s) found", diagnostics.len());
+            }
+        }
+
+        Mode::Workflow => {
+            let blocks = markdown::blocks(&md, &prompts)?;
+            let jobs = workflow::jobs(&blocks)?;
+            for block in workflow::unselected(&blocks, &jobs)? {
+                eprintln!(
+                    "warning: {}: no job selects this block",
+                    run::location(&args.markdown, block)
+                );
+            }
+            print!("{}", workflow::render(&args.markdown, &jobs)?);
+        }
+
+        Mode::Extract => {
+            let cx = args.all_contexts();
+            let source = args.sources.pop().unwrap_or_default();
+            let os = source.load()?;
+            let blocks = markdown::blocks(&md, &prompts)?;
+            if args.explain {
+                explain(&args, &blocks, &cx, &os)?;
+            }
+
+            let selected = select(&args, &blocks, &cx, &os)?;
+            match args.format {
+                Format::Text => selected.iter().for_each(|b| print!("{}", b.script())),
+                Format::Json => {
+                    for block in selected {
+                        println!("{}", block.to_json(&args.markdown));
+                    }
+                }
+                Format::Dockerfile => {
+                    let image = dockerfile::image(&os, &args.images)?;
+                    print!("{}", dockerfile::render(&args.markdown, &image, &selected));
+                }
+                Format::Script => print!(
+                    "{}",
+                    script::render(
+                        &args.markdown,
+                        &source,
+                        &os,
+                        &cx.iter().cloned().collect(),
+                        &selected,
+                        args.preamble()
+                    )
+                ),
+            }
+        }
+
+        Mode::Run => {
+            let cx = args.all_contexts();
+            let os = args.sources.pop().unwrap_or_default().load()?;
+            let blocks = markdown::blocks(&md, &prompts)?;
+            if args.explain {
+                explain(&args, &blocks, &cx, &os)?;
+            }
+
+            let selected = select(&args, &blocks, &cx, &os)?;
+            let count = selected.len();
+            let progress = |block: &_| eprintln!("ok {}", run::location(&args.markdown, block));
+            if let Some(failure) = run::run(selected, args.run_mode, progress)? {
+                bail!("{}", run::report(&args.markdown, &failure));
+            }
+            eprintln!("{} block(s) passed ({})", count, args.run_mode);
+        }
+
+        Mode::Matrix | Mode::Coverage => {
+            if args.sources.is_empty() {
+                args.sources = distro::names().map(|d| Source::Distro(d.into())).collect();
+            }
+            if args.contexts.is_empty() {
+                args.contexts.push(HashSet::new());
+            }
+
+            let blocks = markdown::blocks(&md, &prompts)?;
+            let matrix = matrix::evaluate(&blocks, &args.sources, &args.contexts)?;
+            match (args.mode, &args.output) {
+                (Mode::Coverage, _) => {
+                    let counts = matrix::coverage(&blocks, &matrix);
+                    let file = args.markdown.display();
+                    let mut dead = 0;
+                    for (block, count) in blocks.iter().zip(counts) {
+                        let flag = if count == 0 { "  (never selected)" } else { "" };
+                        println!(
+                            "{}:{}: {}/{} {}{}",
+                            file,
+                            block.line,
+                            count,
+                            matrix.len(),
+                            block.info,
+                            flag
+                        );
+                        dead += usize::from(count == 0);
+                    }
+
+                    if args.fail_on_dead && dead > 0 {
+                        bail!("{} block(s) never selected", dead);
+                    }
+                }
+
+                (_, Some(dir)) => {
+                    unique_file_names(&matrix)?;
+                    std::fs::create_dir_all(dir)?;
+                    for combination in &matrix {
+                        combination.write_script(dir, &args.markdown, args.preamble())?;
+                    }
+                }
+
+                (_, None) => {
+                    for combination in &matrix {
+                        println!("{}", combination.to_json());
+                    }
+                }
             }
         }
     }
@@ -193,4 +327,55 @@ echo enarx
 "#
         );
     }
+
+    #[test]
+    fn malformed() {
+        for md in ["```sh:ID=\necho\n```\n", "```sh:git;ID\necho\n```\n"] {
+            let mut os = OS_RELEASE.as_bytes();
+            assert!(filter_markdown(&HashSet::new(), &mut os, md).is_err());
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let os = doctest::os_release::read(OS_RELEASE.as_bytes()).unwrap();
+        let blocks = markdown::blocks(MARKDOWN, &Default::default()).unwrap();
+        let cx = ["git".to_string()].into_iter().collect();
+
+        let args = ["--section", "Getting Started/Install*", "x"];
+        let args = Args::parse(args.iter().map(|a| a.to_string())).unwrap();
+        let lines = select(&args, &blocks, &cx, &os).unwrap();
+        assert_eq!(lines.iter().map(|b| b.line).collect::<Vec<_>>(), [16]);
+
+        let args = [
+            "--exclude-section",
+            "**/Git*",
+            "--exclude-section",
+            "*/Build Enarx",
+            "x",
+        ];
+        let args = Args::parse(args.iter().map(|a| a.to_string())).unwrap();
+        assert_eq!(
+            outside(&args, &blocks[5]).unwrap(),
+            "in excluded section \"*/Build Enarx\""
+        );
+        let lines = select(&args, &blocks, &cx, &os).unwrap();
+        assert_eq!(lines.iter().map(|b| b.line).collect::<Vec<_>>(), [16]);
+    }
+
+    #[test]
+    fn file_names() {
+        let blocks = markdown::blocks("```sh\necho a\n```\n", &Default::default()).unwrap();
+        let sources = [Source::Distro("debian-12".into())];
+        let contexts = [
+            ["a/b".to_string()].into_iter().collect(),
+            ["a_b".to_string()].into_iter().collect(),
+        ];
+        let matrix = matrix::evaluate(&blocks, &sources, &contexts).unwrap();
+        assert_eq!(
+            unique_file_names(&matrix).unwrap_err().to_string(),
+            "`debian-12` with contexts `a/b` and `debian-12` with contexts `a_b` would both be written to debian-12+a_b.sh"
+        );
+        assert!(unique_file_names(&matrix[..1]).is_ok());
+    }
 }
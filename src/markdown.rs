@@ -0,0 +1,520 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Extraction of filterable code blocks from markdown.
+
+use std::collections::HashSet;
+use std::ops::{Deref, Range};
+use std::path::Path;
+
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Parser, Tag};
+
+use crate::attributes::{self, Attributes};
+use crate::command::{self, Command, Lang, Prompts};
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+use crate::json::Json;
+use crate::os_release::Facts;
+
+trait CodeBlockKindExt {
+    /// Returns the language and filter part of a command block's info string.
+    ///
+    /// The filter is `""` for a plain ```` ```sh ```` block. This is `None`
+    /// for blocks that never produce commands.
+    fn command_filter(&self) -> Option<(Lang, &str)>;
+}
+
+impl CodeBlockKindExt for CodeBlockKind<'_> {
+    fn command_filter(&self) -> Option<(Lang, &str)> {
+        match self {
+            Self::Fenced(k) => {
+                // Include ```sh blocks without a filter
+                let (tag, param) = k.split_once(':').unwrap_or((k.deref(), ""));
+                Lang::from_tag(tag).map(|lang| (lang, param))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A command block together with its parsed filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// The 1-based line of the opening fence.
+    pub line: usize,
+
+    /// The 1-based line of the closing fence, or the last line of the block.
+    pub end_line: usize,
+
+    /// The byte range of the whole block in the document.
+    pub span: Range<usize>,
+
+    /// The texts of the enclosing headings, outermost first.
+    pub headings: Vec<String>,
+
+    /// The raw info string following the opening fence.
+    pub info: String,
+
+    /// How the contents are split into commands.
+    pub lang: Lang,
+
+    /// The filter selecting this block, or `None` if it is always selected.
+    ///
+    /// This combines the filter in the info string with the directives of
+    /// the enclosing sections, see [`blocks`].
+    pub filter: Option<Filter>,
+
+    /// Whether `filter` includes a directive of an enclosing section.
+    pub inherited: bool,
+
+    /// The settings for running this block.
+    pub attributes: Attributes,
+
+    /// The contents of the block.
+    pub text: String,
+
+    /// The commands of the block with shell prompts removed.
+    pub commands: Vec<Command>,
+}
+
+impl Block {
+    /// Returns the text of the nearest preceding heading, if any.
+    pub fn heading(&self) -> Option<&str> {
+        self.headings.last().map(String::as_str)
+    }
+
+    /// Determines whether this code block should be included in output.
+    ///
+    /// This is based on evaluating the block's filter expression against the
+    /// enabled contexts and the KEY=VALUE pairs from `/etc/os-release`.
+    pub fn include(&self, cx: &HashSet<String>, os: &Facts) -> Result<bool> {
+        match &self.filter {
+            Some(f) => f.eval(cx, os).map_err(|e| Error::Block {
+                line: self.line,
+                error: Box::new(e),
+            }),
+            None => Ok(true),
+        }
+    }
+
+    /// Determines whether this block is included and describes why.
+    pub fn explain(&self, cx: &HashSet<String>, os: &Facts) -> Result<(bool, String)> {
+        match &self.filter {
+            Some(f) => f.explain(cx, os).map_err(|e| Error::Block {
+                line: self.line,
+                error: Box::new(e),
+            }),
+            None => Ok((true, "no filter".into())),
+        }
+    }
+}
+
+impl Block {
+    /// Returns the commands of this block, each followed by a newline.
+    pub fn script(&self) -> String {
+        let mut text = String::new();
+        for command in &self.commands {
+            text.push_str(&command.text);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Returns this block as a JSON object, naming the document it is from.
+    pub fn to_json(&self, file: &Path) -> Json {
+        Json::object([
+            ("file", file.display().to_string().into()),
+            ("line", self.line.into()),
+            ("end_line", self.end_line.into()),
+            ("start", self.span.start.into()),
+            ("end", self.span.end.into()),
+            (
+                "headings",
+                self.headings.iter().map(String::as_str).collect(),
+            ),
+            ("info", self.info.as_str().into()),
+            ("filter", self.filter.as_ref().map(Filter::to_json).into()),
+            ("attributes", self.attributes.to_json()),
+            ("lang", self.lang.name().into()),
+            ("text", self.text.as_str().into()),
+            (
+                "commands",
+                self.commands.iter().map(Command::to_json).collect(),
+            ),
+        ])
+    }
+}
+
+/// A fenced code block's info string and its position in the document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fence {
+    /// The 1-based line of the opening fence.
+    pub line: usize,
+
+    /// The 1-based column at which the info string starts.
+    pub column: usize,
+
+    /// The raw info string.
+    pub info: String,
+}
+
+/// Lists the info strings of all fenced code blocks, whatever their language.
+pub fn fences(md: &str) -> Vec<Fence> {
+    let lines = LineIndex::new(md);
+
+    Parser::new(md)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let line = lines.line(range.start);
+                let start = lines.0[line - 1];
+                let fence = md[range.start..].lines().next().unwrap_or_default();
+                let offset = fence.find(info.deref()).unwrap_or(0);
+                Some(Fence {
+                    line,
+                    column: range.start - start + offset + 1,
+                    info: info.to_string(),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// A `<!-- doctest: FILTER -->` directive and its position in the document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Directive {
+    /// The 1-based line of the directive.
+    pub line: usize,
+
+    /// The 1-based column at which the filter starts.
+    pub column: usize,
+
+    /// The raw filter.
+    pub filter: String,
+
+    /// Whether the directive is not followed by a heading, so that it
+    /// applies to no section.
+    pub stray: bool,
+}
+
+/// Lists all directives, see [`blocks`].
+pub fn directives(md: &str) -> Vec<Directive> {
+    let lines = LineIndex::new(md);
+    let mut out: Vec<Directive> = Vec::new();
+    let mut pending = 0;
+
+    for (event, range) in Parser::new(md).into_offset_iter() {
+        match event {
+            Event::Html(..) => {
+                let text = &md[range.clone()];
+                if let Some(filter) = directive(text) {
+                    let line = lines.line(range.start);
+                    let offset = text.find("doctest:").unwrap_or(0) + "doctest:".len();
+                    out.push(Directive {
+                        line,
+                        column: range.start - lines.0[line - 1] + offset + 1,
+                        filter: filter.into(),
+                        stray: true,
+                    });
+                    pending += 1;
+                }
+            }
+            Event::Start(Tag::Heading(..)) => {
+                let start = out.len() - pending;
+                out[start..].iter_mut().for_each(|d| d.stray = false);
+                pending = 0;
+            }
+            Event::Start(..) => pending = 0,
+            _ => (),
+        }
+    }
+
+    out
+}
+
+/// Returns the filter of a `<!-- doctest: FILTER -->` directive.
+fn directive(html: &str) -> Option<&str> {
+    html.trim()
+        .strip_prefix("<!--")?
+        .strip_suffix("-->")?
+        .trim()
+        .strip_prefix("doctest:")
+}
+
+/// Combines filters so that all of them must match.
+fn all(filters: impl IntoIterator<Item = Filter>) -> Option<Filter> {
+    filters
+        .into_iter()
+        .reduce(|l, r| Filter::And(Box::new(l), Box::new(r)))
+}
+
+/// Parses all command code blocks and their filters from a markdown document.
+///
+/// Commands are recognized within blocks by `prompts`. A comment such as
+/// `<!-- doctest: sev && ID=fedora -->` on a line of its own right before a
+/// heading sets a filter for the section, which every block in the section
+/// and its subsections must match in addition to its own filter.
+pub fn blocks(md: &str, prompts: &Prompts) -> Result<Vec<Block>> {
+    let lines = LineIndex::new(md);
+    let mut blocks = Vec::new();
+    let mut current: Option<Block> = None;
+    let mut headings: Vec<(HeadingLevel, String, Option<Filter>)> = Vec::new();
+    let mut in_heading = false;
+    let mut pending: Option<(usize, Filter)> = None;
+
+    for (event, range) in Parser::new(md).into_offset_iter() {
+        match &event {
+            Event::Start(Tag::Heading(..)) | Event::Html(..) => (),
+            Event::Start(..) => {
+                if let Some((line, _)) = pending {
+                    return Err(Error::StrayDirective(line));
+                }
+            }
+            _ => (),
+        }
+
+        match event {
+            Event::Html(html) if directive(&html).is_some() => {
+                let line = lines.line(range.start);
+                let param = directive(&html).unwrap_or_default();
+                let filter = Filter::parse_info(param).map_err(|error| Error::InvalidFilter {
+                    line,
+                    filter: param.trim().into(),
+                    error,
+                })?;
+                let previous = pending.take().map(|(_, f)| f);
+                if let Some(filter) = all(previous.into_iter().chain(filter)) {
+                    pending = Some((line, filter));
+                }
+            }
+
+            Event::Start(Tag::Heading(level, ..)) => {
+                while headings.last().is_some_and(|(l, ..)| *l >= level) {
+                    headings.pop();
+                }
+                headings.push((level, String::new(), pending.take().map(|(_, f)| f)));
+                in_heading = true;
+            }
+
+            Event::End(Tag::Heading(..)) => in_heading = false,
+            Event::Text(text) | Event::Code(text) if in_heading => {
+                if let Some((_, heading, _)) = headings.last_mut() {
+                    heading.push_str(&text);
+                }
+            }
+
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let (lang, param) = match kind.command_filter() {
+                    Some(split) => split,
+                    None => continue,
+                };
+                let line = lines.line(range.start);
+                let (attributes, filter) =
+                    attributes::split(param).map_err(|error| Error::InvalidAttribute {
+                        line,
+                        info: param.into(),
+                        error,
+                    })?;
+                let filter = Filter::parse_info(&filter).map_err(|error| Error::InvalidFilter {
+                    line,
+                    filter: param.into(),
+                    error,
+                })?;
+                let inherited = headings.iter().filter_map(|(.., f)| f.clone());
+                let filter = all(inherited.chain(filter));
+                let info = match kind {
+                    CodeBlockKind::Fenced(info) => info.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                current = Some(Block {
+                    line,
+                    end_line: lines.line(range.end.saturating_sub(1).max(range.start)),
+                    span: range,
+                    headings: headings.iter().map(|(_, h, _)| h.clone()).collect(),
+                    info,
+                    lang,
+                    filter,
+                    inherited: headings.iter().any(|(.., f)| f.is_some()),
+                    attributes,
+                    text: String::new(),
+                    commands: Vec::new(),
+                });
+            }
+
+            Event::End(Tag::CodeBlock(..)) => {
+                if let Some(mut block) = current.take() {
+                    block.commands =
+                        command::parse(block.lang, &block.text, block.line + 1, prompts)?;
+                    blocks.push(block);
+                }
+            }
+            Event::Text(text) => {
+                if let Some(block) = current.as_mut() {
+                    block.text.push_str(&text);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    match pending {
+        Some((line, _)) => Err(Error::StrayDirective(line)),
+        None => Ok(blocks),
+    }
+}
+
+/// Maps byte offsets in a document to 1-based line numbers.
+struct LineIndex(Vec<usize>);
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let starts = text.match_indices('\n').map(|(i, _)| i + 1);
+        Self(std::iter::once(0).chain(starts).collect())
+    }
+
+    fn line(&self, offset: usize) -> usize {
+        match self.0.binary_search(&offset) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let md = "# Title\n\n```sh\necho a\n```\n\n```rust\nfn main() {}\n```\n\n```sh:git\necho b\necho c\n```\n\n```console\n$ uname\nLinux\n```\n";
+        let blocks = super::blocks(md, &Prompts::default()).unwrap();
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].line, 3);
+        assert_eq!(blocks[0].heading(), Some("Title"));
+        assert_eq!(blocks[0].info, "sh");
+        assert_eq!(blocks[0].filter, None);
+        assert_eq!(blocks[0].text, "echo a\n");
+        assert_eq!(blocks[1].line, 11);
+        assert_eq!(blocks[1].filter, Some(Filter::Context("git".into())));
+        assert_eq!(blocks[1].text, "echo b\necho c\n");
+        assert_eq!(blocks[1].lang, Lang::Sh);
+        assert_eq!(blocks[2].lang, Lang::Console);
+
+        let cmds = &blocks[2].commands;
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].line, 17);
+        assert_eq!(cmds[0].text, "uname");
+        assert_eq!(cmds[0].expected.as_deref(), Some("Linux\n"));
+    }
+
+    #[test]
+    fn json() {
+        let md =
+            "# A\n\n## B\n\n### C\n\n## D\n\n```console:git && ID=fedora\n$ uname\nLinux\n```\n";
+        let blocks = super::blocks(md, &Prompts::default()).unwrap();
+        assert_eq!(blocks[0].headings, ["A", "D"]);
+        assert_eq!(blocks[0].span, 24..69);
+        assert_eq!(blocks[0].end_line, 12);
+        assert_eq!(
+            blocks[0].to_json(Path::new("README.md")).to_string(),
+            concat!(
+                r#"{"file":"README.md","line":9,"end_line":12,"start":24,"end":69,"#,
+                r#""headings":["A","D"],"info":"console:git && ID=fedora","#,
+                r#""filter":{"and":[{"context":"git"},{"key":"ID","op":"=","value":"fedora"}]},"#,
+                r#""attributes":{"cwd":null,"env":{},"exit":null,"timeout":null,"retries":null,"#,
+                r#""name":null,"needs":[]},"#,
+                r#""lang":"console","text":"$ uname\nLinux\n","#,
+                r#""commands":[{"line":10,"text":"uname","expected":"Linux\n"}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn directive_positions() {
+        let md = "<!-- doctest: ID=fedora -->\n<!-- doctest:sev -->\n# A\n\n  <!-- doctest: git -->\n\ntext\n\n<!-- doctest: x -->\n";
+        let directives = super::directives(md)
+            .into_iter()
+            .map(|d| (d.line, d.column, d.filter, d.stray))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            directives,
+            [
+                (1, 14, " ID=fedora".into(), false),
+                (2, 14, "sev".into(), false),
+                (5, 16, " git".into(), true),
+                (9, 14, " x".into(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let md = "# Install\n\n<!-- doctest: ID=fedora -->\n## Fedora\n\n\
+                  ```sh\ndnf install -y gcc\n```\n\n\
+                  <!-- doctest: sev -->\n### SEV\n\n```sh:git\nsevctl ok\n```\n\n\
+                  ## Debian\n\n```sh:ID=debian\napt install -y gcc\n```\n";
+        let blocks = super::blocks(md, &Prompts::default()).unwrap();
+        let filters = blocks
+            .iter()
+            .map(|b| (b.inherited, b.filter.as_ref().map(Filter::to_string)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            filters,
+            [
+                (true, Some("ID=fedora".into())),
+                (true, Some("ID=fedora && sev && git".into())),
+                (false, Some("ID=debian".into())),
+            ]
+        );
+
+        for md in [
+            "<!-- doctest: ID=fedora -->\n\ntext\n\n# A\n",
+            "# A\n\n<!-- doctest: sev -->\n",
+        ] {
+            let err = super::blocks(md, &Prompts::default()).unwrap_err();
+            assert!(err
+                .to_string()
+                .ends_with(": doctest directive must be followed by a heading"));
+        }
+
+        let err = super::blocks("<!-- doctest: ID= -->\n# A\n", &Prompts::default()).unwrap_err();
+        assert!(err.to_string().starts_with("line 1: invalid filter `ID=`"));
+    }
+
+    #[test]
+    fn fences() {
+        let md = "text\n\n  ```  sh:git\n```\n\n~~~rust\n~~~\n\n    indented\n";
+        assert_eq!(
+            super::fences(md),
+            vec![
+                Fence {
+                    line: 3,
+                    column: 8,
+                    info: "sh:git".into()
+                },
+                Fence {
+                    line: 6,
+                    column: 4,
+                    info: "rust".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let err = super::blocks("text\n\n```sh:git &&\n```\n", &Prompts::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3: invalid filter `git &&`: column 7: expected context or predicate"
+        );
+
+        let err = super::blocks("```sh:git cwd=a env=A\n```\n", &Prompts::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: invalid attribute in `git cwd=a env=A`: column 15: expected `env=NAME=VALUE`"
+        );
+    }
+}
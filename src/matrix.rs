@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Evaluation of code block filters across many sources and context sets.
+
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::json::Json;
+use crate::markdown::Block;
+use crate::os_release::Facts;
+use crate::script;
+use crate::source::Source;
+
+/// The blocks selected for one combination of source and context set.
+#[derive(Debug)]
+pub struct Combination<'a> {
+    /// The os-release source.
+    pub source: &'a Source,
+
+    /// The os-release variables read from the source.
+    pub os: Facts,
+
+    /// The enabled contexts, sorted.
+    pub contexts: BTreeSet<String>,
+
+    /// The selected blocks, in document order.
+    pub blocks: Vec<&'a Block>,
+}
+
+/// Replaces the characters of `text` that are unsafe in a file name, such
+/// as `/`, with `_`.
+fn sanitize(text: &str) -> String {
+    text.replace(
+        |c: char| !c.is_ascii_alphanumeric() && !".-_".contains(c),
+        "_",
+    )
+}
+
+/// Evaluates every block against every combination of source and context set.
+///
+/// Each source is loaded once and the blocks are parsed by the caller, so the
+/// cost of a combination is only that of evaluating the filters.
+pub fn evaluate<'a>(
+    blocks: &'a [Block],
+    sources: &'a [Source],
+    contexts: &[HashSet<String>],
+) -> Result<Vec<Combination<'a>>> {
+    let mut out = Vec::new();
+
+    for source in sources {
+        let os = source.load()?;
+        for cx in contexts {
+            let mut selected = Vec::new();
+            for block in blocks {
+                if block.include(cx, &os)? {
+                    selected.push(block);
+                }
+            }
+
+            out.push(Combination {
+                source,
+                os: os.clone(),
+                contexts: cx.iter().cloned().collect(),
+                blocks: selected,
+            });
+        }
+    }
+
+    Ok(out)
+}
+
+/// Counts how many combinations selected each block.
+///
+/// The counts are returned in the same order as `blocks`.
+pub fn coverage(blocks: &[Block], matrix: &[Combination<'_>]) -> Vec<usize> {
+    blocks
+        .iter()
+        .map(|block| {
+            matrix
+                .iter()
+                .filter(|c| c.blocks.iter().any(|b| std::ptr::eq(*b, block)))
+                .count()
+        })
+        .collect()
+}
+
+impl Combination<'_> {
+    /// Returns the cleaned command lines of all selected blocks.
+    pub fn commands(&self) -> Vec<String> {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.commands)
+            .map(|c| c.text.clone())
+            .collect()
+    }
+
+    /// Returns a file name identifying this combination, e.g. `fedora-40+git.sh`.
+    pub fn file_name(&self) -> String {
+        let mut name = sanitize(&self.source.to_string())
+            .trim_start_matches('_')
+            .to_string();
+        if name.is_empty() {
+            name.push_str("root");
+        }
+        for cx in &self.contexts {
+            name.push('+');
+            name.push_str(&sanitize(cx));
+        }
+        name.push_str(".sh");
+        name
+    }
+
+    /// Returns this combination as a JSON object.
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("source", self.source.to_string().into()),
+            (
+                "contexts",
+                self.contexts.iter().map(String::as_str).collect(),
+            ),
+            ("blocks", self.blocks.iter().map(|b| b.line).collect()),
+            ("commands", self.commands().into_iter().collect()),
+        ])
+    }
+
+    /// Writes the selected blocks of `file` as a script into `dir`.
+    pub fn write_script(&self, dir: &Path, file: &Path, preamble: &str) -> Result<()> {
+        let script = script::render(
+            file,
+            self.source,
+            &self.os,
+            &self.contexts,
+            &self.blocks,
+            preamble,
+        );
+        let path = dir.join(self.file_name());
+        std::fs::write(&path, script)
+            .map_err(Error::io(format!("failed to write {}", path.display())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::Prompts;
+    use crate::markdown;
+
+    const MARKDOWN: &str = r#"
+```sh:like=debian
+echo debian
+```
+
+```sh:ID=fedora && VERSION_ID>=40
+echo fedora
+```
+
+```sh:git
+echo git
+```
+"#;
+
+    #[test]
+    fn evaluate() {
+        let blocks = markdown::blocks(MARKDOWN, &Prompts::default()).unwrap();
+        let sources = ["ubuntu-22.04", "fedora-39", "fedora-40"].map(|d| Source::Distro(d.into()));
+        let contexts = [HashSet::new(), ["git".to_string()].into_iter().collect()];
+
+        let matrix = super::evaluate(&blocks, &sources, &contexts).unwrap();
+        let results = matrix
+            .iter()
+            .map(|c| (c.file_name(), c.commands().join(";")))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            [
+                ("ubuntu-22.04.sh", "echo debian"),
+                ("ubuntu-22.04+git.sh", "echo debian;echo git"),
+                ("fedora-39.sh", ""),
+                ("fedora-39+git.sh", "echo git"),
+                ("fedora-40.sh", "echo fedora"),
+                ("fedora-40+git.sh", "echo fedora;echo git"),
+            ]
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+        );
+
+        assert_eq!(
+            matrix[1].to_json().to_string(),
+            r#"{"source":"ubuntu-22.04","contexts":["git"],"blocks":[2,10],"commands":["echo debian","echo git"]}"#
+        );
+
+        let sources = [Source::Distro("fedora-40".into())];
+        let contexts = [["../../x".to_string(), "git".into()].into_iter().collect()];
+        let matrix = super::evaluate(&blocks, &sources, &contexts).unwrap();
+        assert_eq!(matrix[0].file_name(), "fedora-40+.._.._x+git.sh");
+    }
+
+    #[test]
+    fn coverage() {
+        let md = format!("{}\n```sh:ID=fedorra\necho typo\n```\n", MARKDOWN);
+        let blocks = markdown::blocks(&md, &Prompts::default()).unwrap();
+        let sources = ["debian-12", "fedora-40"].map(|d| Source::Distro(d.into()));
+        let contexts = [HashSet::new(), ["git".to_string()].into_iter().collect()];
+
+        let matrix = super::evaluate(&blocks, &sources, &contexts).unwrap();
+        assert_eq!(super::coverage(&blocks, &matrix), vec![2, 2, 2, 0]);
+    }
+}
@@ -0,0 +1,262 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! A parser for the freedesktop.org `os-release` file format.
+//!
+//! Each line is a `KEY=VALUE` assignment using a subset of shell syntax:
+//! values may be unquoted, 'single quoted' or "double quoted", and a
+//! backslash escapes the following character. Lines starting with `#` are
+//! comments, blank lines are ignored and CRLF line endings are accepted.
+//!
+//! See <https://www.freedesktop.org/software/systemd/man/os-release.html>.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// The variables of an os-release file, keyed by name.
+pub type Facts = HashMap<String, String>;
+
+/// The os-release locations to search, in order of preference.
+const PATHS: &[&str] = &["etc/os-release", "usr/lib/os-release"];
+
+/// The keys defined by the os-release specification.
+pub const KEYS: &[&str] = &[
+    "NAME",
+    "ID",
+    "ID_LIKE",
+    "PRETTY_NAME",
+    "CPE_NAME",
+    "VARIANT",
+    "VARIANT_ID",
+    "VERSION",
+    "VERSION_ID",
+    "VERSION_CODENAME",
+    "BUILD_ID",
+    "IMAGE_ID",
+    "IMAGE_VERSION",
+    "RELEASE_TYPE",
+    "HOME_URL",
+    "DOCUMENTATION_URL",
+    "SUPPORT_URL",
+    "BUG_REPORT_URL",
+    "PRIVACY_POLICY_URL",
+    "SUPPORT_END",
+    "LOGO",
+    "ANSI_COLOR",
+    "VENDOR_NAME",
+    "VENDOR_URL",
+    "EXPERIMENT",
+    "EXPERIMENT_URL",
+    "DEFAULT_HOSTNAME",
+    "ARCHITECTURE",
+    "SYSEXT_LEVEL",
+    "CONFEXT_LEVEL",
+    "SYSEXT_SCOPE",
+    "CONFEXT_SCOPE",
+    "PORTABLE_PREFIXES",
+];
+
+/// The maximum number of symlinks followed when resolving inside a root.
+const MAX_SYMLINKS: usize = 40;
+
+/// Finds the os-release file of the system mounted at `root`.
+///
+/// `/etc/os-release` is preferred over `/usr/lib/os-release`. Symlinks are
+/// resolved relative to `root` rather than the host, so that an absolute
+/// link like `/etc/os-release -> /usr/lib/os-release` inside an unpacked
+/// image does not escape to the host's file.
+pub fn locate(root: &Path) -> Result<PathBuf> {
+    PATHS
+        .iter()
+        .filter_map(|path| resolve(root, Path::new(path)))
+        .find(|path| path.is_file())
+        .ok_or_else(|| Error::NoOsRelease(root.into()))
+}
+
+/// Resolves `path` inside `root`, following symlinks of its final component.
+fn resolve(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut path = root.join(path);
+
+    for _ in 0..MAX_SYMLINKS {
+        let target = match path.read_link() {
+            Ok(target) => target,
+            Err(..) => return Some(path),
+        };
+
+        path = if target.is_absolute() {
+            let relative = target
+                .components()
+                .filter(|c| !matches!(c, Component::RootDir | Component::Prefix(..)));
+            root.join(relative.collect::<PathBuf>())
+        } else {
+            path.parent()?.join(target)
+        };
+    }
+
+    None
+}
+
+/// Reads and parses an os-release file.
+pub fn read(mut reader: impl Read) -> Result<Facts> {
+    let mut input = String::new();
+    reader
+        .read_to_string(&mut input)
+        .map_err(Error::io("failed to read os-release"))?;
+    parse(&input)
+}
+
+/// Parses the contents of an os-release file into its assignments.
+///
+/// Later assignments to the same key replace earlier ones.
+pub fn parse(input: &str) -> Result<Facts> {
+    let mut vars = HashMap::new();
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = parse_line(line).map_err(|message| Error::OsRelease {
+            line: n + 1,
+            message,
+            text: line.into(),
+        })?;
+        vars.insert(key.into(), value);
+    }
+
+    Ok(vars)
+}
+
+/// Parses a single `KEY=VALUE` assignment.
+fn parse_line(line: &str) -> Result<(&str, String), String> {
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| String::from("expected KEY=VALUE"))?;
+
+    if key.is_empty()
+        || key.starts_with(|c: char| c.is_ascii_digit())
+        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(format!("invalid key `{}`", key));
+    }
+
+    Ok((key, unquote(value)?))
+}
+
+/// Removes shell quoting and escapes from a value.
+fn unquote(value: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => out.push(c),
+                    None => return Err("unterminated single quote".into()),
+                }
+            },
+
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('$' | '"' | '\\' | '`')) => out.push(c),
+                        Some(c) => {
+                            out.push('\\');
+                            out.push(c);
+                        }
+                        None => return Err("unterminated double quote".into()),
+                    },
+                    Some(c) => out.push(c),
+                    None => return Err("unterminated double quote".into()),
+                }
+            },
+
+            '\\' => match chars.next() {
+                Some(c) => out.push(c),
+                None => return Err("trailing backslash".into()),
+            },
+
+            c if c.is_whitespace() => return Err("unquoted whitespace in value".into()),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let os = parse(concat!(
+            "# A comment\r\n",
+            "\r\n",
+            "PRETTY_NAME=\"Debian GNU/Linux 11 (bullseye)\"\r\n",
+            "ID=debian\r\n",
+            "ID_LIKE='rhel centos fedora'\n",
+            "ESCAPED=\"a \\\"quoted\\\" \\$value\\\\ \\n\"\n",
+            "SPACED=with\\ space\n",
+            "MIXED=a\"b c\"'d e'\n",
+            "EMPTY=\n",
+            "  # indented comment\n",
+            "ID=ubuntu\n",
+        ))
+        .unwrap();
+
+        assert_eq!(os["PRETTY_NAME"], "Debian GNU/Linux 11 (bullseye)");
+        assert_eq!(os["ID"], "ubuntu");
+        assert_eq!(os["ID_LIKE"], "rhel centos fedora");
+        assert_eq!(os["ESCAPED"], "a \"quoted\" $value\\ \\n");
+        assert_eq!(os["SPACED"], "with space");
+        assert_eq!(os["MIXED"], "ab cd e");
+        assert_eq!(os["EMPTY"], "");
+        assert_eq!(os.len(), 7);
+    }
+
+    #[test]
+    fn locate() {
+        let root = std::env::temp_dir().join(format!("doctest-locate-{}", std::process::id()));
+        let etc = root.join("etc");
+        let lib = root.join("usr/lib");
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::create_dir_all(&lib).unwrap();
+
+        assert!(super::locate(&root).is_err());
+
+        std::fs::write(lib.join("os-release"), "ID=fedora\n").unwrap();
+        assert_eq!(super::locate(&root).unwrap(), lib.join("os-release"));
+
+        std::os::unix::fs::symlink("/usr/lib/os-release", etc.join("os-release")).unwrap();
+        assert_eq!(super::locate(&root).unwrap(), lib.join("os-release"));
+
+        std::fs::remove_file(etc.join("os-release")).unwrap();
+        std::fs::write(etc.join("os-release"), "ID=debian\n").unwrap();
+        assert_eq!(super::locate(&root).unwrap(), etc.join("os-release"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let err = |input| parse(input).unwrap_err().to_string();
+
+        assert_eq!(
+            err("ID=debian\nNAME"),
+            "invalid os-release line 2: expected KEY=VALUE: NAME"
+        );
+        assert!(err("1D=x").contains("invalid key `1D`"));
+        assert!(err("MY-KEY=x").contains("invalid key `MY-KEY`"));
+        assert!(err("NAME=\"Debian").contains("unterminated double quote"));
+        assert!(err("NAME='Debian").contains("unterminated single quote"));
+        assert!(err("NAME=Debian Linux").contains("unquoted whitespace"));
+    }
+}
@@ -0,0 +1,638 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Execution of selected code blocks.
+
+use std::env;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{self, Child, ChildStdin, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::attributes::Attributes;
+use crate::command::Command;
+use crate::error::{Error, Result};
+use crate::expect;
+use crate::lexer::Continuation;
+use crate::markdown::Block;
+
+/// The shell that runs commands, the same as the interpreter of scripts
+/// generated with [`crate::script`].
+pub const SHELL: &str = "bash";
+
+/// How shells are shared between blocks.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Every block runs in a fresh shell, so that blocks cannot affect each
+    /// other through the working directory or environment.
+    #[default]
+    Isolated,
+
+    /// All blocks run in one shell session, so that the working directory
+    /// and environment carry forward as when following the document by hand.
+    Session,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Isolated => "fresh shell per block",
+            Self::Session => "shared shell session",
+        })
+    }
+}
+
+/// The result of executing one command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// The exit status of the command.
+    pub status: i32,
+
+    /// The combined stdout and stderr of the command.
+    pub output: String,
+
+    /// Whether the command was killed for running past its deadline.
+    pub timed_out: bool,
+
+    /// Whether the shell exited while running the command, e.g. because the
+    /// command ran `exit`. `status` is then the shell's exit status.
+    pub exited: bool,
+}
+
+/// A shell process that executes commands one at a time.
+///
+/// Commands are written to the shell's stdin, each followed by a unique
+/// marker carrying its exit status, so that the output and status of every
+/// command can be told apart while the shell keeps its state between them.
+/// The shell's output is read on a separate thread, so that waiting for it
+/// can time out.
+pub struct Shell {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<io::Result<Vec<u8>>>,
+    marker: String,
+}
+
+impl Shell {
+    /// Starts a new [`SHELL`] process in a process group of its own.
+    pub fn spawn() -> Result<Self> {
+        let mut child = process::Command::new(SHELL)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .process_group(0)
+            .spawn()
+            .map_err(Error::io(format!("failed to start {}", SHELL)))?;
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut line = Vec::new();
+            let read = stdout.read_until(b'\n', &mut line);
+            let end = !matches!(read, Ok(n) if n > 0);
+            if sender.send(read.map(|_| line)).is_err() || end {
+                break;
+            }
+        });
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        Ok(Self {
+            stdin: child.stdin.take().unwrap(),
+            lines,
+            marker: format!("__doctest_{}_{}__", std::process::id(), nanos),
+            child,
+        })
+    }
+
+    /// Executes a command and waits for it to finish.
+    ///
+    /// The command's stdin is `/dev/null` and its stderr is merged into the
+    /// captured output.
+    pub fn exec(&mut self, command: &str) -> Result<Outcome> {
+        self.exec_until(command, None)
+    }
+
+    /// Executes a command and waits for it to finish until `deadline`.
+    ///
+    /// When the deadline passes, the shell and everything it started are
+    /// killed and the outcome is marked as timed out. The shell cannot run
+    /// further commands after that.
+    ///
+    /// An unfinished command, such as one with an unmatched quote, is
+    /// refused, since the shell would wait for the rest of it forever.
+    pub fn exec_until(&mut self, command: &str, deadline: Option<Instant>) -> Result<Outcome> {
+        let mut state = Continuation::new();
+        command.lines().for_each(|line| state.feed(line));
+        if !state.is_complete() {
+            return Err(Error::Shell(format!(
+                "refusing to run unfinished command `{}`",
+                command
+            )));
+        }
+
+        // The command is sent with a single write, since the shell may exit
+        // and close its stdin as soon as it has read the command.
+        let input = format!(
+            "{{\n{}\n}} </dev/null 2>&1\nprintf '%s %d\\n' {} $?\n",
+            command, self.marker
+        );
+        match self
+            .stdin
+            .write_all(input.as_bytes())
+            .and_then(|_| self.stdin.flush())
+        {
+            // The shell exited, which the loop below reports once its
+            // remaining output has been read.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            result => result.map_err(Error::io(format!("failed to write to {}", SHELL)))?,
+        }
+
+        let mut output = Vec::new();
+        loop {
+            let received = match deadline {
+                Some(deadline) => self
+                    .lines
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self
+                    .lines
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            let line = match received {
+                Ok(Ok(line)) if !line.is_empty() => line,
+                Ok(Err(e)) => return Err(Error::io(format!("failed to read from {}", SHELL))(e)),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.kill();
+                    return Ok(Outcome {
+                        status: -1,
+                        output: String::from_utf8_lossy(&output).into(),
+                        timed_out: true,
+                        exited: true,
+                    });
+                }
+                Ok(Ok(_)) | Err(RecvTimeoutError::Disconnected) => {
+                    // The shell exited, e.g. because the command ran `exit`.
+                    let status = self
+                        .child
+                        .wait()
+                        .map_err(Error::io(format!("failed to wait for {}", SHELL)))?
+                        .code()
+                        .unwrap_or(-1);
+                    return Ok(Outcome {
+                        status,
+                        output: String::from_utf8_lossy(&output).into(),
+                        timed_out: false,
+                        exited: true,
+                    });
+                }
+            };
+
+            let start = output.len();
+            output.extend(line);
+            let line = String::from_utf8_lossy(&output[start..]).into_owned();
+            if let Some(pos) = line.find(&self.marker) {
+                let status = line[pos + self.marker.len()..]
+                    .trim()
+                    .parse()
+                    .map_err(|_| {
+                        Error::Shell(format!("malformed status from {}: {}", SHELL, line))
+                    })?;
+                output.truncate(start + pos);
+                return Ok(Outcome {
+                    status,
+                    output: String::from_utf8_lossy(&output).into(),
+                    timed_out: false,
+                    exited: false,
+                });
+            }
+        }
+    }
+
+    /// Returns whether the shell has exited or was killed.
+    pub fn exited(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Kills the shell's process group, including commands it started.
+    fn kill(&mut self) {
+        let group = format!("-{}", self.child.id());
+        let _ = process::Command::new("kill")
+            .args(["-s", "KILL", "--", &group])
+            .stderr(Stdio::null())
+            .status();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    /// Changes into the directory and exports the variables of `attributes`,
+    /// where the directory is relative to the working directory of doctest.
+    ///
+    /// `line` is the line of the block the attributes belong to.
+    pub fn apply(&mut self, attributes: &Attributes, line: usize) -> Result<()> {
+        // A relative directory is resolved here, since a shared shell may
+        // have been left in another directory by earlier blocks.
+        let mut attributes = attributes.clone();
+        if let Some(cwd) = &mut attributes.cwd {
+            let dir = env::current_dir().map_err(Error::io("failed to get working directory"))?;
+            *cwd = dir.join(&cwd).display().to_string();
+        }
+
+        for command in attributes.setup() {
+            let outcome = self.exec(&command)?;
+            if outcome.status != 0 {
+                return Err(Error::Block {
+                    line,
+                    error: Box::new(Error::Shell(format!(
+                        "`{}` failed: {}",
+                        command,
+                        outcome.output.trim_end()
+                    ))),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The delay before the first retry of a failed block, doubled for each
+/// further retry.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// A command that did not succeed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure<'a> {
+    /// The block containing the command.
+    pub block: &'a Block,
+
+    /// The failing command.
+    pub command: &'a Command,
+
+    /// The command's outcome.
+    pub outcome: Outcome,
+
+    /// A diff from the expected to the actual output, if they differ.
+    pub diff: Option<String>,
+
+    /// How the blocks were run.
+    pub mode: Mode,
+
+    /// How many times the block was run.
+    pub attempts: u32,
+}
+
+/// Runs the commands of a block once, stopping at the first failing command.
+///
+/// A command succeeds if it exits with status 0 or the block's `exit`
+/// attribute and, in a console session, prints the expected output. The
+/// block's `timeout` applies to all of its commands together.
+fn attempt<'a>(shell: &mut Shell, block: &'a Block, mode: Mode) -> Result<Option<Failure<'a>>> {
+    let attributes = &block.attributes;
+    shell.apply(attributes, block.line)?;
+
+    let deadline = attributes.timeout.map(|timeout| Instant::now() + timeout);
+    for command in block.commands.iter().filter(|c| !c.is_comment()) {
+        let outcome = shell.exec_until(&command.text, deadline)?;
+        let diff = match &command.expected {
+            Some(expected) if !expect::matches(expected, &outcome.output) => {
+                Some(expect::diff(expected, &outcome.output, command.next_line()))
+            }
+            _ => None,
+        };
+        let status = outcome.status == 0 || Some(outcome.status) == attributes.exit;
+        if outcome.exited || !status || diff.is_some() {
+            return Ok(Some(Failure {
+                block,
+                command,
+                outcome,
+                diff,
+                mode,
+                attempts: 1,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Runs the blocks in order, stopping at the first failing command.
+///
+/// A command fails if it exits with a non-zero status other than the one
+/// given by the block's `exit` attribute, if it runs past the block's
+/// `timeout` or if its output does not match the expected output shown in a
+/// console session. A failed block is run again up to `retries` times, in a
+/// fresh shell unless in a session, waiting longer before each retry.
+///
+/// The `cwd` and `env` attributes of a block are applied before its first
+/// command; in a session they carry forward to later blocks. A session is
+/// restarted, losing its state, after its shell exits or a command times
+/// out. `progress` is called after each block that succeeds.
+pub fn run<'a>(
+    blocks: impl IntoIterator<Item = &'a Block>,
+    mode: Mode,
+    mut progress: impl FnMut(&Block),
+) -> Result<Option<Failure<'a>>> {
+    let mut session = None;
+    for block in blocks {
+        let attempts = block.attributes.retries.unwrap_or(0) + 1;
+        let mut backoff = BACKOFF;
+        for n in 1..=attempts {
+            if mode == Mode::Isolated || session.is_none() {
+                session = Some(Shell::spawn()?);
+            }
+            let shell = session.as_mut().unwrap();
+
+            let failure = match attempt(shell, block, mode)? {
+                Some(failure) => failure,
+                None => break,
+            };
+            if shell.exited() {
+                session = None;
+            }
+            if n == attempts {
+                return Ok(Some(Failure {
+                    attempts,
+                    ..failure
+                }));
+            }
+
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+        progress(block);
+    }
+
+    Ok(None)
+}
+
+/// Describes a block's position for reports, e.g. `README.md:12 (Build)`.
+pub fn location(file: &Path, block: &Block) -> String {
+    match block.heading() {
+        Some(heading) => format!("{}:{} ({})", file.display(), block.line, heading),
+        None => format!("{}:{}", file.display(), block.line),
+    }
+}
+
+/// Formats a failure report naming the block, command and its output.
+pub fn report(file: &Path, failure: &Failure<'_>) -> String {
+    let mut notes = failure.mode.to_string();
+    if failure.attempts > 1 {
+        notes.push_str(&format!(", {} attempts", failure.attempts));
+    }
+
+    if let Some(diff) = failure.diff.as_ref().filter(|_| !failure.outcome.timed_out) {
+        return format!(
+            "{}: output of command on line {} did not match ({}): {}\n\n{}",
+            location(file, failure.block),
+            failure.command.line,
+            notes,
+            failure.command.text,
+            diff.trim_end()
+        );
+    }
+
+    let verdict = match failure.block.attributes.timeout {
+        Some(timeout) if failure.outcome.timed_out => format!("timed out after {:?}", timeout),
+        _ if failure.outcome.exited && failure.outcome.status == 0 => "failed: shell exited".into(),
+        _ if failure.outcome.exited => format!(
+            "failed: shell exited with status {}",
+            failure.outcome.status
+        ),
+        _ => format!("failed with exit status {}", failure.outcome.status),
+    };
+    let mut message = format!(
+        "{}: command on line {} {} ({}): {}",
+        location(file, failure.block),
+        failure.command.line,
+        verdict,
+        notes,
+        failure.command.text
+    );
+    if !failure.outcome.output.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(failure.outcome.output.trim_end());
+    }
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::Prompts;
+    use crate::markdown;
+
+    #[test]
+    fn shell() {
+        let mut shell = Shell::spawn().unwrap();
+
+        let out = shell.exec("cd /tmp && X=1 && echo start").unwrap();
+        assert_eq!(
+            out,
+            Outcome {
+                status: 0,
+                output: "start\n".into(),
+                timed_out: false,
+                exited: false,
+            }
+        );
+
+        let out = shell.exec("printf %s \"$X\"; pwd >&2").unwrap();
+        assert_eq!(out.output, "1/tmp\n");
+
+        let err = shell.exec("echo don't panic").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "refusing to run unfinished command `echo don't panic`"
+        );
+
+        let out = shell
+            .exec("a=(x y); [[ ${a[1]} == y ]] && echo bash")
+            .unwrap();
+        assert_eq!(out.output, "bash\n");
+
+        let out = shell.exec("printf partial; false").unwrap();
+        assert_eq!(
+            out,
+            Outcome {
+                status: 1,
+                output: "partial".into(),
+                timed_out: false,
+                exited: false,
+            }
+        );
+
+        let out = shell.exec("exit 3").unwrap();
+        assert_eq!((out.status, out.exited), (3, true));
+
+        for (command, status) in [("exit 3", 3), ("exit 0", 0)] {
+            let mut shell = Shell::spawn().unwrap();
+            let out = shell.exec(command).unwrap();
+            assert_eq!(
+                out,
+                Outcome {
+                    status,
+                    output: String::new(),
+                    timed_out: false,
+                    exited: true,
+                }
+            );
+            assert!(shell.exec("true").unwrap().exited);
+        }
+    }
+
+    #[test]
+    fn run() {
+        let md = "# A\n\n```sh\necho a\n```\n\n# B\n\n```sh\necho b\nsh -c 'echo oops; exit 2'\necho c\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+
+        let mut passed = Vec::new();
+        let failure = super::run(&blocks, Mode::Isolated, |b| passed.push(b.line))
+            .unwrap()
+            .unwrap();
+        assert_eq!(passed, vec![3]);
+        assert_eq!(failure.block.line, 9);
+        assert_eq!(failure.command.text, "sh -c 'echo oops; exit 2'");
+        assert_eq!(failure.command.line, 11);
+        assert_eq!(
+            failure.outcome,
+            Outcome {
+                status: 2,
+                output: "oops\n".into(),
+                timed_out: false,
+                exited: false,
+            }
+        );
+
+        assert_eq!(
+            report(Path::new("README.md"), &failure),
+            "README.md:9 (B): command on line 11 failed with exit status 2 (fresh shell per block): sh -c 'echo oops; exit 2'\n\noops"
+        );
+    }
+
+    #[test]
+    fn expected() {
+        let md = "# A\n\n```console\n$ echo a; echo b\na\n...\n$ printf 'x\\ny\\n'\nx\nz\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+
+        let failure = super::run(&blocks, Mode::Isolated, |_| ())
+            .unwrap()
+            .unwrap();
+        assert_eq!(failure.command.line, 7);
+        assert_eq!(failure.outcome.status, 0);
+        assert_eq!(
+            report(Path::new("README.md"), &failure),
+            "README.md:3 (A): output of command on line 7 did not match (fresh shell per block): printf 'x\\ny\\n'\n\n\
+             --- expected\n+++ actual\n@@ -8,2 +1,2 @@\n x\n-z\n+y"
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let md = "```sh:cwd=/ env=\"GREETING=hello world\"\ncd /tmp\n```\n\n```console\n$ pwd; echo \"$GREETING\"\n/tmp\nhello world\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+
+        let failure = super::run(&blocks, Mode::Session, |_| ()).unwrap();
+        assert_eq!(failure, None);
+
+        let failure = super::run(&blocks, Mode::Isolated, |_| ())
+            .unwrap()
+            .unwrap();
+        assert_eq!(failure.block.line, 5);
+        assert_eq!(failure.mode, Mode::Isolated);
+
+        let md = "```console:cwd=/tmp env=GREETING=hi\n$ pwd; echo \"$GREETING\"\n/tmp\nhi\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        assert_eq!(super::run(&blocks, Mode::Isolated, |_| ()).unwrap(), None);
+
+        let md = "# A\n\n```sh:cwd=/nonexistent\ntrue\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let err = super::run(&blocks, Mode::Isolated, |_| ()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("line 3: `cd -- /nonexistent` failed: "));
+    }
+
+    #[test]
+    fn attributes() {
+        let marker = std::env::temp_dir().join(format!("doctest-retry-{}", process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let md = format!(
+            "```sh:exit=3\nsh -c 'exit 3'\n```\n\n```sh:retries=1\ntest -e {0} || {{ touch {0}; false; }}\n```\n",
+            marker.display()
+        );
+        let blocks = markdown::blocks(&md, &Prompts::default()).unwrap();
+        let mut passed = Vec::new();
+        let failure = super::run(&blocks, Mode::Isolated, |b| passed.push(b.line)).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(failure, None);
+        assert_eq!(passed, [1, 5]);
+
+        let md = "```sh:exit=3 retries=1\nsh -c 'exit 2'\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let failure = super::run(&blocks, Mode::Session, |_| ()).unwrap().unwrap();
+        assert_eq!(failure.attempts, 2);
+        assert_eq!(
+            report(Path::new("README.md"), &failure),
+            "README.md:1: command on line 2 failed with exit status 2 (shared shell session, 2 attempts): sh -c 'exit 2'"
+        );
+
+        let md =
+            "```sh:cwd=src\ncd ..\n```\n\n```sh:cwd=src retries=1\ntest -f lib.rs\nfalse\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let failure = super::run(&blocks, Mode::Session, |_| ()).unwrap().unwrap();
+        assert_eq!((failure.command.line, failure.attempts), (7, 2));
+
+        let md = "```sh:timeout=200ms\necho started\nsleep 30\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let start = Instant::now();
+        let failure = super::run(&blocks, Mode::Isolated, |_| ())
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(failure.outcome.timed_out);
+        assert_eq!(
+            report(Path::new("README.md"), &failure),
+            "README.md:1: command on line 3 timed out after 200ms (fresh shell per block): sleep 30"
+        );
+    }
+
+    #[test]
+    fn exited() {
+        for (md, report) in [
+            (
+                "```sh\nexit 3\n```\n",
+                "README.md:1: command on line 2 failed: shell exited with status 3 (fresh shell per block): exit 3",
+            ),
+            (
+                "```sh\ntrue\nexit 0\n```\n",
+                "README.md:1: command on line 3 failed: shell exited (fresh shell per block): exit 0",
+            ),
+        ] {
+            let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+            let failure = super::run(&blocks, Mode::Isolated, |_| ())
+                .unwrap()
+                .unwrap();
+            assert_eq!(super::report(Path::new("README.md"), &failure), report);
+        }
+
+        let md = "```sh\ntrue\nexit 3\n```\n\n```sh\ntrue\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let failure = super::run(&blocks, Mode::Session, |_| ()).unwrap().unwrap();
+        assert_eq!((failure.command.line, failure.outcome.status), (3, 3));
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rendering of selected blocks as a standalone shell script.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::markdown::Block;
+use crate::os_release::Facts;
+use crate::run;
+use crate::source::Source;
+
+/// The interpreter line of generated scripts, which matches [`run::SHELL`].
+pub const SHEBANG: &str = "#!/usr/bin/env bash";
+
+/// The default commands run before the first block.
+pub const PREAMBLE: &str = "set -euo pipefail";
+
+/// Describes the os-release facts a script was generated for, e.g.
+/// `ID=fedora VERSION_ID=40 (Fedora Linux 40)`.
+fn identity(os: &Facts) -> String {
+    let mut out = ["ID", "VERSION_ID"]
+        .iter()
+        .filter_map(|key| os.get(*key).map(|value| format!("{}={}", key, value)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some(name) = os.get("PRETTY_NAME") {
+        out.push_str(&format!(" ({})", name));
+    }
+    match out.trim_start() {
+        "" => "unknown".into(),
+        out => out.into(),
+    }
+}
+
+/// Renders a script running the commands of `blocks` in order.
+///
+/// The header records the markdown file, the os-release source and the
+/// contexts the blocks were selected for, and each block is preceded by a
+/// comment giving its location, so that a failing line can be traced back
+/// to the documentation.
+///
+/// A block with a `cwd` or `env` attribute runs in a subshell, so that its
+/// directory and variables apply to that block only, as when it is run. A
+/// command of a block with an `exit` attribute also succeeds with that
+/// status.
+pub fn render(
+    file: &Path,
+    source: &Source,
+    os: &Facts,
+    contexts: &BTreeSet<String>,
+    blocks: &[&Block],
+    preamble: &str,
+) -> String {
+    let contexts = match contexts.is_empty() {
+        true => "(none)".into(),
+        false => contexts.iter().cloned().collect::<Vec<_>>().join(", "),
+    };
+
+    let mut script = format!(
+        "{}\n# Generated by doctest from {}\n# os-release: {} from {}\n# contexts: {}\n",
+        SHEBANG,
+        file.display(),
+        identity(os),
+        source,
+        contexts
+    );
+    if !preamble.is_empty() {
+        script.push_str(preamble);
+        script.push('\n');
+    }
+
+    for block in blocks {
+        script.push_str(&format!("\n# {}\n", run::location(file, block)));
+        let setup = block.attributes.setup();
+        if !setup.is_empty() {
+            script.push_str("(\n");
+        }
+        for line in &setup {
+            script.push_str(line);
+            script.push('\n');
+        }
+        for command in &block.commands {
+            match command.is_comment() {
+                true => script.push_str(&command.text),
+                false => script.push_str(&block.attributes.accept(&command.text)),
+            }
+            script.push('\n');
+        }
+        if !setup.is_empty() {
+            script.push_str(")\n");
+        }
+    }
+
+    script
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::Prompts;
+    use crate::{distro, markdown, os_release};
+
+    #[test]
+    fn render() {
+        let md = "# Install\n\n```sh\n# Update first\napt update\n```\n\n## Build\n\n```sh:git\nmake\n```\n\n\
+                  ```sh:cwd=build env=CC=clang exit=2\n# Configure\n./configure\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let os = os_release::read(distro::get("debian-12").unwrap().as_bytes()).unwrap();
+        let contexts = ["git".to_string()].into_iter().collect();
+
+        let script = super::render(
+            Path::new("README.md"),
+            &Source::Distro("debian-12".into()),
+            &os,
+            &contexts,
+            &blocks.iter().collect::<Vec<_>>(),
+            PREAMBLE,
+        );
+        assert_eq!(
+            script,
+            "#!/usr/bin/env bash
+# Generated by doctest from README.md
+# os-release: ID=debian VERSION_ID=12 (Debian GNU/Linux 12 (bookworm)) from debian-12
+# contexts: git
+set -euo pipefail
+
+# README.md:3 (Install)
+# Update first
+apt update
+
+# README.md:10 (Build)
+make
+
+# README.md:14 (Build)
+(
+cd -- build
+export CC=clang
+# Configure
+{
+./configure
+} || [ $? -eq 2 ]
+)
+"
+        );
+
+        let script = super::render(
+            Path::new("README.md"),
+            &Source::default(),
+            &Facts::new(),
+            &BTreeSet::new(),
+            &[],
+            "",
+        );
+        assert_eq!(
+            script,
+            "#!/usr/bin/env bash\n# Generated by doctest from README.md\n# os-release: unknown from /\n# contexts: (none)\n"
+        );
+    }
+}
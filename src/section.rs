@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Selection of blocks by the headings they are nested in.
+
+use std::fmt;
+
+/// A pattern matching a section of a document by its heading path, e.g.
+/// `Getting Started/Install*`.
+///
+/// Each `/`-separated segment is a glob matching one heading, where `*`
+/// matches any text, `?` matches one character and a backslash escapes the
+/// next character, such as a `/` within a heading. A segment of `**`
+/// matches any number of headings. A section includes its subsections, so
+/// a pattern matches every block whose heading path starts with headings
+/// matching it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pattern: String,
+    segments: Vec<String>,
+}
+
+impl Section {
+    /// Parses a section pattern.
+    pub fn new(pattern: &str) -> Self {
+        let mut segments = vec![String::new()];
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '/' => segments.push(String::new()),
+                '\\' => {
+                    let segment = segments.last_mut().unwrap();
+                    segment.push(c);
+                    segment.extend(chars.next());
+                }
+                c => segments.last_mut().unwrap().push(c),
+            }
+        }
+
+        Self {
+            pattern: pattern.into(),
+            segments: segments.iter().map(|s| s.trim().into()).collect(),
+        }
+    }
+
+    /// Returns whether a block with the heading path `headings`, outermost
+    /// first, is within this section.
+    pub fn matches(&self, headings: &[String]) -> bool {
+        path(&self.segments, headings)
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Returns whether the leading headings match the pattern segments.
+fn path(segments: &[String], headings: &[String]) -> bool {
+    match segments.split_first() {
+        None => true,
+        Some((segment, rest)) if segment == "**" => {
+            (0..=headings.len()).any(|i| path(rest, &headings[i..]))
+        }
+        Some((segment, rest)) => match headings.split_first() {
+            Some((heading, headings)) => {
+                let pattern = segment.chars().collect::<Vec<_>>();
+                let text = heading.trim().chars().collect::<Vec<_>>();
+                glob(&pattern, &text) && path(rest, headings)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Returns whether `text` matches the glob `pattern`.
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        Some(('?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some(('\\', [c, rest @ ..])) | Some((c, rest)) => {
+            text.first() == Some(c) && glob(rest, &text[1..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let path = |headings: &[&str]| headings.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let fedora = path(&["Getting Started", "Install Dependencies", "Fedora"]);
+        let build = path(&["Getting Started", "Build Enarx"]);
+        let debian = path(&["Getting Started", "Install Dependencies", "Debian/Ubuntu"]);
+
+        let section = Section::new("Getting Started/Build Enarx");
+        assert!(section.matches(&build));
+        assert!(!section.matches(&fedora));
+        assert!(!section.matches(&path(&["Getting Started"])));
+        assert_eq!(section.to_string(), "Getting Started/Build Enarx");
+
+        let section = Section::new("Getting Started / Install*");
+        assert!(section.matches(&fedora));
+        assert!(section.matches(&debian));
+        assert!(!section.matches(&build));
+
+        assert!(Section::new("**/Fedora").matches(&fedora));
+        assert!(!Section::new("*/Fedora").matches(&fedora));
+        assert!(Section::new("*/*/Fedor?").matches(&fedora));
+        assert!(Section::new(r"**/Debian\/Ubuntu").matches(&debian));
+        assert!(!Section::new("**/Debian").matches(&debian));
+        assert!(Section::new("**").matches(&[]));
+        assert!(!Section::new("*").matches(&[]));
+    }
+}
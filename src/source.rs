@@ -0,0 +1,63 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sources of os-release facts.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::os_release::Facts;
+use crate::{distro, os_release};
+
+/// Where to read os-release variables from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// An explicit os-release file.
+    File(PathBuf),
+
+    /// The os-release file discovered inside a root filesystem.
+    Root(PathBuf),
+
+    /// A built-in distribution fixture.
+    Distro(String),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::Root("/".into())
+    }
+}
+
+impl Source {
+    /// Opens the raw os-release file of this source.
+    pub fn open(&self) -> Result<Box<dyn Read>> {
+        let open = |path: &Path| File::open(path).map_err(Error::io("failed to open os-release"));
+        Ok(match self {
+            Self::File(path) => Box::new(open(path)?),
+            Self::Root(root) => Box::new(open(&os_release::locate(root)?)?),
+            Self::Distro(name) => Box::new(distro::get(name)?.as_bytes()),
+        })
+    }
+
+    /// Reads and parses the os-release variables from this source.
+    pub fn load(&self) -> Result<Facts> {
+        self.open()
+            .and_then(os_release::read)
+            .map_err(|e| Error::Source {
+                source: self.to_string(),
+                error: Box::new(e),
+            })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) | Self::Root(path) => write!(f, "{}", path.display()),
+            Self::Distro(name) => f.write_str(name),
+        }
+    }
+}
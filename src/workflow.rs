@@ -0,0 +1,406 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generation of a CI workflow covering the filters used in a document.
+
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+
+use crate::error::Result;
+use crate::filter::{Filter, Op};
+use crate::lexer::quote;
+use crate::markdown::Block;
+use crate::os_release::Facts;
+use crate::{distro, dockerfile, os_release};
+
+/// One CI job: a built-in distro, if any, and the enabled contexts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    /// The built-in distro to select blocks for, or `None` for the host.
+    pub distro: Option<&'static str>,
+
+    /// The contexts to enable, sorted.
+    pub contexts: BTreeSet<String>,
+}
+
+impl Job {
+    /// Returns the job identifier, e.g. `ubuntu-22_04-git-sev`.
+    fn id(&self) -> String {
+        let mut id = self.distro.unwrap_or("host").to_string();
+        for context in &self.contexts {
+            id.push('-');
+            id.push_str(context);
+        }
+        id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+    }
+
+    /// Returns whether this job selects `block`.
+    ///
+    /// The os-release facts of the host are unknown, so on the host only
+    /// predicates that hold without any facts are considered to match.
+    pub fn selects(&self, block: &Block) -> Result<bool> {
+        let cx = self.contexts.iter().cloned().collect();
+        Ok(block.include(&cx, &facts(self.distro)?).unwrap_or(false))
+    }
+}
+
+/// Returns the os-release facts of a built-in distro, or none for the host.
+fn facts(distro: Option<&str>) -> Result<Facts> {
+    match distro {
+        Some(name) => os_release::read(distro::get(name)?.as_bytes()),
+        None => Ok(Facts::new()),
+    }
+}
+
+/// The `ID`s of distributions whose base images lack bash, which
+/// `doctest run` needs, so that no job runs on them.
+const WITHOUT_BASH: &[&str] = &["alpine"];
+
+/// Returns whether a predicate positively identifies a distribution, such
+/// as `ID=fedora`, `ID_LIKE~=debian` or `like=rhel`.
+fn identifies(leaf: &Filter) -> bool {
+    match leaf {
+        Filter::Like(..) => true,
+        Filter::Os { key, op, .. } => {
+            matches!(key.as_str(), "ID" | "ID_LIKE") && matches!(op, Op::Eq | Op::In)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the contexts referenced by a filter, sorted.
+fn contexts(filter: &Filter) -> BTreeSet<String> {
+    filter
+        .leaves()
+        .into_iter()
+        .filter_map(|leaf| match leaf {
+            Filter::Context(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Infers the jobs needed to exercise the filters of `blocks`.
+///
+/// A built-in distro is included when some predicate identifying a
+/// distribution matches it, and every context is exercised on its own as
+/// well as not at all. A block needing several contexts at once, such as
+/// `git && sev`, also gets the smallest set of its contexts that selects it
+/// on some distro. Without any predicate identifying a distribution, the
+/// jobs run on the host. Distros whose images lack bash are left out, so
+/// that blocks only selected there are reported by [`unselected`].
+pub fn jobs(blocks: &[Block]) -> Result<Vec<Job>> {
+    let leaves = blocks
+        .iter()
+        .filter_map(|b| b.filter.as_ref())
+        .flat_map(Filter::leaves)
+        .collect::<Vec<_>>();
+
+    let none = HashSet::new();
+    let mut distros = Vec::new();
+    for name in distro::names() {
+        let os = facts(Some(name))?;
+        if os
+            .get("ID")
+            .is_some_and(|id| WITHOUT_BASH.contains(&id.as_str()))
+        {
+            continue;
+        }
+        let matched = leaves
+            .iter()
+            .filter(|leaf| identifies(leaf))
+            .any(|leaf| leaf.eval(&none, &os).unwrap_or(false));
+        if matched {
+            distros.push(Some(name));
+        }
+    }
+    if distros.is_empty() {
+        distros.push(None);
+    }
+
+    let referenced = blocks
+        .iter()
+        .filter_map(|b| b.filter.as_ref())
+        .flat_map(contexts)
+        .collect::<BTreeSet<_>>();
+    let mut sets = std::iter::once(BTreeSet::new())
+        .chain(referenced.into_iter().map(|c| [c].into()))
+        .collect::<Vec<BTreeSet<String>>>();
+
+    let product = |sets: &[BTreeSet<String>]| {
+        distros
+            .iter()
+            .flat_map(|&distro| {
+                sets.iter().map(move |contexts| Job {
+                    distro,
+                    contexts: contexts.clone(),
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for block in blocks {
+        let own = match &block.filter {
+            Some(filter) => contexts(filter).into_iter().collect::<Vec<_>>(),
+            None => continue,
+        };
+        if own.len() < 2 || any_selects(&product(&sets), block)? {
+            continue;
+        }
+
+        // The search is bounded, since a filter rarely names many contexts.
+        let mut subsets = (0..1u64 << own.len().min(16)).collect::<Vec<_>>();
+        subsets.sort_by_key(|mask| mask.count_ones());
+        for mask in subsets {
+            let set = (0..own.len())
+                .filter(|i| mask & 1 << i != 0)
+                .map(|i| own[i].clone())
+                .collect::<BTreeSet<_>>();
+            if any_selects(&product(std::slice::from_ref(&set)), block)? {
+                sets.push(set);
+                break;
+            }
+        }
+    }
+
+    Ok(product(&sets))
+}
+
+/// Returns whether any of `jobs` selects `block`.
+fn any_selects(jobs: &[Job], block: &Block) -> Result<bool> {
+    for job in jobs {
+        if job.selects(block)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the blocks with a filter that none of `jobs` selects.
+pub fn unselected<'a>(blocks: &'a [Block], jobs: &[Job]) -> Result<Vec<&'a Block>> {
+    let mut out = Vec::new();
+    for block in blocks {
+        if block.filter.is_some() && !any_selects(jobs, block)? {
+            out.push(block);
+        }
+    }
+    Ok(out)
+}
+
+/// The job building the `doctest` binary that the other jobs run.
+///
+/// The binary is linked statically, so that it runs in containers of any
+/// distro, and is the version of doctest that generated the workflow.
+const BUILD: &str = concat!(
+    "  build:
+    runs-on: ubuntu-latest
+    steps:
+      - run: rustup target add x86_64-unknown-linux-musl
+      - run: cargo install --locked --target x86_64-unknown-linux-musl --root dist --version ",
+    env!("CARGO_PKG_VERSION"),
+    " ",
+    env!("CARGO_PKG_NAME"),
+    "
+      - uses: actions/upload-artifact@v4
+        with:
+          name: doctest
+          path: dist/bin/doctest
+"
+);
+
+/// Renders a GitHub Actions workflow with one job per entry of `jobs`.
+///
+/// Each job runs in a container of the distro's base image and executes the
+/// document with `doctest run`, using the binary built by a first job. Jobs
+/// whose identifiers would clash are told apart by a numeric suffix.
+pub fn render(file: &Path, jobs: &[Job]) -> Result<String> {
+    let file = quote(&file.display().to_string());
+    let mut out = format!(
+        "# Generated by doctest from {}\nname: doctest\n\non: [push, pull_request]\n\njobs:\n{}",
+        file, BUILD
+    );
+
+    let mut ids = HashSet::from(["build".to_string()]);
+    for job in jobs {
+        let mut id = job.id();
+        for n in 2.. {
+            if ids.insert(id.clone()) {
+                break;
+            }
+            id = format!("{}-{}", job.id(), n);
+        }
+
+        let mut args = vec!["doctest".to_string(), "run".into()];
+        out.push_str(&format!(
+            "  {}:\n    needs: build\n    runs-on: ubuntu-latest\n",
+            id
+        ));
+        if let Some(name) = job.distro {
+            let os = os_release::read(distro::get(name)?.as_bytes())?;
+            out.push_str(&format!(
+                "    container: {}\n",
+                dockerfile::image(&os, &[])?
+            ));
+            args.extend(["--distro".into(), name.into()]);
+        }
+        if !job.contexts.is_empty() {
+            let contexts = job.contexts.iter().cloned().collect::<Vec<_>>();
+            args.extend(["--context".into(), quote(&contexts.join(","))]);
+        }
+        args.push(file.clone());
+
+        out.push_str(
+            "    steps:
+      - uses: actions/checkout@v4
+      - uses: actions/download-artifact@v4
+        with:
+          name: doctest
+          path: /usr/local/bin
+      - run: chmod +x /usr/local/bin/doctest
+",
+        );
+        out.push_str(&format!("      - run: {}\n", args.join(" ")));
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::Prompts;
+    use crate::markdown;
+
+    #[test]
+    fn jobs() {
+        let md = "```sh:ID=fedora && VERSION_ID>=40\n```\n\n```sh:git || like=debian\n```\n\n```sh:ID!=arch && !sev\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let jobs = super::jobs(&blocks).unwrap();
+
+        let distros = jobs
+            .iter()
+            .filter_map(|j| j.distro)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            distros.into_iter().collect::<Vec<_>>(),
+            [
+                "debian-11",
+                "debian-12",
+                "fedora-39",
+                "fedora-40",
+                "ubuntu-20.04",
+                "ubuntu-22.04",
+                "ubuntu-24.04"
+            ]
+        );
+        assert_eq!(jobs.len(), 7 * 3);
+        assert!(jobs[0].contexts.is_empty());
+        assert_eq!(jobs[1].contexts, ["git".to_string()].into());
+        assert_eq!(jobs[2].contexts, ["sev".to_string()].into());
+
+        let blocks = markdown::blocks("```sh:git\n```\n", &Prompts::default()).unwrap();
+        let jobs = super::jobs(&blocks).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(
+            jobs[1],
+            Job {
+                distro: None,
+                contexts: ["git".to_string()].into()
+            }
+        );
+
+        let md = "```sh:git && sev && !tdx\n```\n\n```sh:git && (sev || tdx)\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let jobs = super::jobs(&blocks).unwrap();
+        let sets = jobs
+            .iter()
+            .map(|j| j.contexts.iter().cloned().collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>();
+        assert_eq!(sets, ["", "git", "sev", "tdx", "git,sev"]);
+        assert!(super::unselected(&blocks, &jobs).unwrap().is_empty());
+
+        let md =
+            "```sh:ID=fedora\n```\n\n```sh:ID=fedora && ID=debian\n```\n\n```sh:ID=alpine\n```\n";
+        let blocks = markdown::blocks(md, &Prompts::default()).unwrap();
+        let jobs = super::jobs(&blocks).unwrap();
+        let unselected = super::unselected(&blocks, &jobs).unwrap();
+        assert!(!jobs
+            .iter()
+            .any(|j| j.distro.is_some_and(|d| d.starts_with("alpine"))));
+        assert_eq!(
+            unselected.iter().map(|b| b.line).collect::<Vec<_>>(),
+            [4, 7]
+        );
+    }
+
+    #[test]
+    fn render() {
+        let jobs = [
+            Job {
+                distro: Some("ubuntu-22.04"),
+                contexts: ["git".to_string(), "sev".to_string()].into(),
+            },
+            Job {
+                distro: None,
+                contexts: ["git-sev".to_string()].into(),
+            },
+            Job {
+                distro: None,
+                contexts: ["git".to_string(), "sev".to_string()].into(),
+            },
+        ];
+        assert_eq!(
+            super::render(Path::new("docs/My README.md"), &jobs).unwrap(),
+            "# Generated by doctest from 'docs/My README.md'
+name: doctest
+
+on: [push, pull_request]
+
+jobs:
+  build:
+    runs-on: ubuntu-latest
+    steps:
+      - run: rustup target add x86_64-unknown-linux-musl
+      - run: cargo install --locked --target x86_64-unknown-linux-musl --root dist --version 0.1.0 doctest
+      - uses: actions/upload-artifact@v4
+        with:
+          name: doctest
+          path: dist/bin/doctest
+  ubuntu-22_04-git-sev:
+    needs: build
+    runs-on: ubuntu-latest
+    container: ubuntu:22.04
+    steps:
+      - uses: actions/checkout@v4
+      - uses: actions/download-artifact@v4
+        with:
+          name: doctest
+          path: /usr/local/bin
+      - run: chmod +x /usr/local/bin/doctest
+      - run: doctest run --distro ubuntu-22.04 --context git,sev 'docs/My README.md'
+  host-git-sev:
+    needs: build
+    runs-on: ubuntu-latest
+    steps:
+      - uses: actions/checkout@v4
+      - uses: actions/download-artifact@v4
+        with:
+          name: doctest
+          path: /usr/local/bin
+      - run: chmod +x /usr/local/bin/doctest
+      - run: doctest run --context git-sev 'docs/My README.md'
+  host-git-sev-2:
+    needs: build
+    runs-on: ubuntu-latest
+    steps:
+      - uses: actions/checkout@v4
+      - uses: actions/download-artifact@v4
+        with:
+          name: doctest
+          path: /usr/local/bin
+      - run: chmod +x /usr/local/bin/doctest
+      - run: doctest run --context git,sev 'docs/My README.md'
+"
+        );
+    }
+}